@@ -4,7 +4,7 @@
 
 #import bevy_eidolon::render::utils
 #import bevy_eidolon::render::bindings::instance_uniforms
-#import bevy_eidolon::render::io_types::{VertexOutput, Vertex}
+#import bevy_eidolon::render::io_types::VertexOutput
 
 struct CustomMaterialUniform {
     color: vec4<f32>,
@@ -17,6 +17,16 @@ struct CustomMaterialUniform {
 @group(3) @binding(1) var base_color_texture: texture_2d<f32>;
 @group(3) @binding(2) var base_color_sampler: sampler;
 
+// The instance attributes of `WaveInstance`.
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+
+    @location(8) i_pos_scale: vec4<f32>,
+    @location(9) i_rotation: f32,
+    @location(13) i_phase: f32,
+};
+
 @vertex
 fn vertex(vertex: Vertex) -> VertexOutput {
     var out: VertexOutput;
@@ -24,7 +34,8 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     var local_position = vertex.position;
 
     local_position.y += sin((vertex.position.x + vertex.position.z) * material.frequency + globals.time
-                                                                                            * material.speed)
+                                                                                            * material.speed
+                                                                                            + vertex.i_phase)
                                                                                             * material.amplitude;
 
     let final_matrix = utils::calculate_instance_world_matrix(
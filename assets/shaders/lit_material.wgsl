@@ -6,6 +6,9 @@
 
 #import bevy_eidolon::render::utils
 #import bevy_eidolon::render::bindings::instance_uniforms
+#ifdef BATCHED_INSTANCES
+#import bevy_eidolon::render::bindings::load_instance_uniforms
+#endif
 #import bevy_eidolon::render::io_types::{VertexOutput, Vertex}
 
 struct CustomMaterialUniform {
@@ -18,8 +21,11 @@ struct CustomMaterialUniform {
 
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+#ifdef BATCHED_INSTANCES
+    load_instance_uniforms(in.uniform_index);
+#endif
     let tex_color = textureSample(base_color_texture, base_color_sampler, in.uv);
-    let base_color = material.color * tex_color * instance_uniforms.color;
+    let base_color = material.color * tex_color * instance_uniforms.color * in.color;
 
     var pbr_input: pbr_types::PbrInput = pbr_types::pbr_input_new();
 
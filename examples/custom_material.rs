@@ -1,4 +1,5 @@
-/// Showcases how to override the fragment and vertex shader, as well as usage of material keys and custom shader defines.
+/// Showcases how to override the fragment and vertex shader, as well as usage of material keys, custom shader defines
+/// and custom instance data.
 #[path = "utils/example.rs"]
 mod example;
 
@@ -14,6 +15,7 @@ use bevy_mesh::{CuboidMeshBuilder, Mesh, Mesh3d, MeshBuilder, MeshVertexBufferLa
 use bevy_reflect::TypePath;
 use bevy_render::render_resource::{
     AsBindGroup, RenderPipelineDescriptor, ShaderType, SpecializedMeshPipelineError,
+    VertexAttribute, VertexFormat,
 };
 use bevy_utils::default;
 
@@ -24,7 +26,8 @@ use bevy_transform::prelude::Transform;
 use example::*;
 
 use bevy_camera::prelude::Visibility;
-use std::sync::Arc;
+use bytemuck::{Pod, Zeroable};
+use std::mem::offset_of;
 
 #[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
 #[uniform(0, CustomMaterialUniform)]
@@ -76,6 +79,27 @@ impl From<&CustomMaterial> for CustomMaterialUniform {
     }
 }
 
+// Offsets the wave of each instance, available to the vertex shader at location 13.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+struct WaveInstance {
+    data: InstanceData,
+    phase: f32,
+    _padding: [f32; 3],
+}
+
+impl InstanceType for WaveInstance {
+    const LAYOUT: InstanceLayout = InstanceLayout::new(
+        InstanceBase::Compact,
+        size_of::<Self>(),
+        &[VertexAttribute {
+            format: VertexFormat::Float32,
+            offset: offset_of!(WaveInstance, phase) as u64,
+            shader_location: 13,
+        }],
+    );
+}
+
 impl InstancedMaterial for CustomMaterial {
     fn vertex_shader() -> ShaderRef {
         "shaders/custom_material.wgsl".into()
@@ -90,8 +114,10 @@ impl InstancedMaterial for CustomMaterial {
         _layout: &MeshVertexBufferLayoutRef,
         key: Self::Data,
     ) -> Result<(), SpecializedMeshPipelineError> {
-        if key.is_red {
-            let fragment = descriptor.fragment.as_mut().unwrap();
+        // Shadow views have no fragment shader.
+        if key.is_red
+            && let Some(fragment) = descriptor.fragment.as_mut()
+        {
             fragment.shader_defs.push("IS_RED".into());
         }
         Ok(())
@@ -133,13 +159,17 @@ fn setup(
     const SIZE: i32 = 10;
     const SPACING: f32 = 2.5;
 
-    let instances: Vec<InstanceData> = (-SIZE..SIZE)
+    let instances: Vec<WaveInstance> = (-SIZE..SIZE)
         .enumerate()
         .flat_map(|(i, x)| {
-            (-SIZE..SIZE).map(move |z| InstanceData {
-                position: Vec3::new(x as f32 * SPACING, 0.0, z as f32 * SPACING),
-                scale: 1.0,
-                index: i as u32,
+            (-SIZE..SIZE).map(move |z| WaveInstance {
+                data: InstanceData {
+                    position: Vec3::new(x as f32 * SPACING, 0.0, z as f32 * SPACING),
+                    scale: 1.0,
+                    index: i as u32,
+                    ..default()
+                },
+                phase: (x + z) as f32 * 0.5,
                 ..default()
             })
         })
@@ -148,7 +178,7 @@ fn setup(
     let (instances, red_instances) = instances.iter().fold(
         (Vec::new(), Vec::new()),
         |(mut data, mut red_data), instance| {
-            if instance.index % 2 == 0 {
+            if instance.data.index % 2 == 0 {
                 data.push(instance.clone());
             } else {
                 red_data.push(instance.clone());
@@ -158,13 +188,13 @@ fn setup(
     );
 
     let instance_material_data = InstanceMaterialData {
-        instances: Arc::new(instances),
+        instances: instances.into(),
         color: Color::WHITE.into(),
         visibility_range: [0.0, 0.0, 1000.0, 1000.0].into(),
     };
 
     let red_instance_material_data = InstanceMaterialData {
-        instances: Arc::new(red_instances),
+        instances: red_instances.into(),
         color: Color::WHITE.into(),
         visibility_range: [0.0, 0.0, 1000.0, 1000.0].into(),
     };
@@ -4,7 +4,10 @@ mod example;
 use bevy_app::{App, AppExit, Startup};
 use bevy_asset::{Assets, RenderAssetUsages};
 use bevy_camera::primitives::Aabb;
-use bevy_color::palettes::tailwind::*;
+use bevy_color::{
+    Mix,
+    palettes::{basic::WHITE, tailwind::*},
+};
 use bevy_ecs::prelude::*;
 use bevy_eidolon::prelude::*;
 use bevy_math::{Vec3, Vec3A};
@@ -13,7 +16,6 @@ use bevy_render::render_resource::PolygonMode;
 use bevy_utils::default;
 
 use example::*;
-use std::sync::Arc;
 
 fn main() -> AppExit {
     App::new()
@@ -54,19 +56,22 @@ fn setup(
 
     const SIZE: i32 = 10;
 
-    let instances = (-SIZE..SIZE)
+    let instances: Vec<InstanceData> = (-SIZE..SIZE)
         .enumerate()
-        .map(|(i, x)| InstanceData {
-            position: Vec3::new(x as f32, 0.25 * 4., x as f32),
-            scale: 4.0,
-            index: i as u32,
-            ..default()
+        .map(|(i, x)| {
+            InstanceData {
+                position: Vec3::new(x as f32, 0.25 * 4., x as f32),
+                scale: 4.0,
+                index: i as u32,
+                ..default()
+            }
+            .with_color(GREEN_500.mix(&SKY_500, i as f32 / (2 * SIZE) as f32))
         })
         .collect();
 
     let instance_material_data = InstanceMaterialData {
-        instances: Arc::new(instances),
-        color: GREEN_500.into(),
+        instances: instances.into(),
+        color: WHITE.into(),
         visibility_range: [0.0, 0.0, 1000.0, 1000.0].into(),
     };
 
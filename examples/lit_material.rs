@@ -21,7 +21,6 @@ use bevy_transform::prelude::Transform;
 use example::*;
 
 use bevy_camera::prelude::Visibility;
-use std::sync::Arc;
 
 #[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
 #[uniform(0, LitMaterialUniform)]
@@ -96,7 +95,7 @@ fn setup(
         .collect();
 
     let instance_material_data = InstanceMaterialData {
-        instances: Arc::new(instances),
+        instances: instances.into(),
         color: Color::WHITE.into(),
         visibility_range: [0.0, 0.0, 1000.0, 1000.0].into(),
     };
@@ -14,7 +14,7 @@ use bevy_ecs::prelude::*;
 use bevy_eidolon::prelude::*;
 use bevy_inspector_egui::quick::ResourceInspectorPlugin;
 use bevy_math::{Quat, Vec3, Vec3A};
-use bevy_mesh::{Indices, Mesh, Mesh3d, PrimitiveTopology};
+use bevy_mesh::{Mesh, Mesh3d, PrimitiveTopology};
 use bevy_reflect::Reflect;
 use bevy_render::render_resource::PolygonMode;
 use bevy_transform::prelude::Transform;
@@ -23,7 +23,6 @@ use bevy_utils::default;
 use example::*;
 
 use rand::{Rng, rng};
-use std::sync::Arc;
 
 fn main() -> AppExit {
     App::new()
@@ -31,12 +30,14 @@ fn main() -> AppExit {
         .insert_resource(ExamplePluginOptions {
             show_inspector: true,
         })
+        // Cull all chunks in a single dispatch.
+        .insert_resource(GpuCullMode::Arena)
         .add_plugins((
             ExamplePlugin,
             ResourceInspectorPlugin::<StressTestConfig>::default(),
             InstancedMaterialCorePlugin,
             InstancedMaterialPlugin::<StandardInstancedMaterial>::default(),
-            GpuComputeCullPlugin,
+            GpuComputeCullPlugin::default(),
         ))
         .add_systems(Startup, setup)
         .add_systems(
@@ -159,7 +160,7 @@ fn setup(
             let color = Color::hsl(hue, 0.7, 0.5).to_linear();
 
             let instance_material_data = InstanceMaterialData {
-                instances: Arc::new(instances),
+                instances: instances.into(),
                 color,
                 visibility_range: [0.0, 0.0, 2000.0, 2000.0].into(),
             };
@@ -176,6 +177,11 @@ fn setup(
                 instance_material_data,
                 // Use GPU driven cull pipeline
                 GpuCullCompute,
+                // Bounds of a single instance for frustum culling in the cull pass.
+                InstanceBoundingSphere {
+                    center: single_aabb.center.into(),
+                    radius: single_aabb.half_extents.length(),
+                },
                 // Disable frustum culling or provide aabb.
                 // NoFrustumCulling,
                 Aabb {
@@ -203,11 +209,14 @@ fn stress_test_chunk_replacement(
         &Transform,
         &Aabb,
         &Mesh3d,
+        &InstanceBoundingSphere,
     )>,
 ) {
     let mut rng = rng();
 
-    for (chunk_grid_pos, entity, instance_data, material, tf, aabb, mesh) in &mut query {
+    for (chunk_grid_pos, entity, instance_data, material, tf, aabb, mesh, bounding_sphere) in
+        &mut query
+    {
         if !rng.random_bool(0.01) {
             continue;
         }
@@ -234,6 +243,7 @@ fn stress_test_chunk_replacement(
             mesh.clone(),
             instance_data,
             GpuCullCompute,
+            *bounding_sphere,
             aabb.clone(),
         ));
     }
@@ -247,13 +257,10 @@ struct LineStrip {
 
 impl From<LineStrip> for Mesh {
     fn from(line: LineStrip) -> Self {
-        let point_count = line.points.len();
         Mesh::new(
             PrimitiveTopology::LineStrip,
             RenderAssetUsages::RENDER_WORLD,
         )
         .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, line.points)
-        // Required for GPU culling (Indexed drawing)
-        .with_inserted_indices(Indices::U32((0..point_count as u32).collect()))
     }
 }
@@ -9,7 +9,6 @@ use bevy_ecs::prelude::*;
 use bevy_math::{Vec3, Vec3A};
 use bevy_mesh::{Indices, Mesh, Mesh3d, PrimitiveTopology};
 use bevy_utils::default;
-use std::sync::Arc;
 
 use bevy_eidolon::prelude::*;
 use example::*;
@@ -48,7 +47,7 @@ fn setup(
 
     const SIZE: i32 = 10;
 
-    let instances = (-SIZE..SIZE)
+    let instances: Vec<InstanceData> = (-SIZE..SIZE)
         .enumerate()
         .map(|(i, x)| InstanceData {
             position: Vec3::new(x as f32, 0.25 * 4., x as f32),
@@ -59,7 +58,7 @@ fn setup(
         .collect();
 
     let instance_material_data = InstanceMaterialData {
-        instances: Arc::new(instances),
+        instances: instances.into(),
         color: GREEN_500.into(),
         visibility_range: [0.0, 0.0, 1000.0, 1000.0].into(),
     };
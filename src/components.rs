@@ -1,35 +1,203 @@
-use bevy_color::prelude::*;
+use bevy_asset::{AssetId, Handle, UntypedAssetId};
+use bevy_color::{ColorToPacked, prelude::*};
 use bevy_derive::{Deref, DerefMut};
 use bevy_ecs::{prelude::*, query::QueryItem};
-use bevy_math::{Mat4, Vec3, Vec4};
+use bevy_math::{Mat4, Quat, UVec2, Vec3, Vec4};
+use bevy_mesh::Mesh;
 use bevy_reflect::Reflect;
 use bevy_render::{
+    Extract,
     extract_component::ExtractComponent,
-    render_resource::{BindGroup, Buffer},
+    render_resource::{BindGroup, Buffer, Texture, TextureView, VertexAttribute},
+    sync_world::{MainEntity, RenderEntity},
 };
+use bevy_shader::ShaderDefVal;
 use bevy_utils::default;
 
 use bytemuck::{Pod, Zeroable};
 
 use crate::prelude::InstancedMaterial;
+use crate::resources::ExtractedInstanceMaterialData;
 
-use bevy_transform::prelude::GlobalTransform;
+use bevy_transform::prelude::{GlobalTransform, Transform};
+use std::any::Any;
 use std::fmt;
 use std::hash::Hash;
+use std::ops::Range;
 use std::sync::Arc;
 
 /// Marker component to opt in to GPU-driven culling/preparation.
 #[derive(Component, Clone, Copy, Default, ExtractComponent)]
+#[require(VisibleInstanceCount)]
 pub struct GpuCullCompute;
 
-/// Sets the material color.
+/// Number of instances of a [`GpuCullCompute`] entity that survived the GPU cull pass,
+/// summed over all views and LOD levels.
 ///
-/// Corresponds to `instance_uniforms.color` in shaders.
-#[derive(Component, Clone, Copy, Debug, Reflect, Default)]
+/// The draw arguments are read back without stalling the GPU, so the count lags a few frames
+/// behind. See [`InstanceCullDiagnostics`](crate::cull::readback::InstanceCullDiagnostics) for
+/// the totals of all entities.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
 #[reflect(Component, Clone, Debug)]
-pub struct InstanceColor(pub Color);
+pub struct VisibleInstanceCount(pub u32);
 
-#[derive(Clone, Copy, Pod, Zeroable, Default)]
+/// Opt in to hierarchical-Z occlusion culling in the GPU cull pass.
+///
+/// Requires [`GpuCullCompute`] and a camera with a `DepthPrepass`. Instances are tested
+/// against a depth pyramid built from the previous frame's depth prepass.
+#[derive(Component, Clone, Copy, Default, ExtractComponent)]
+#[require(GpuCullCompute)]
+pub struct GpuOcclusionCull;
+
+/// Keep the source order of the visible instances in the GPU cull pass.
+///
+/// Visible instances are compacted with a prefix sum instead of an atomic counter, which avoids
+/// flickering of coplanar instances at the cost of an extra dispatch. Can be enabled for all
+/// entities with [`GpuComputeCullPlugin::stable_compaction`](crate::cull::plugin::GpuComputeCullPlugin::stable_compaction).
+#[derive(Component, Clone, Copy, Default, ExtractComponent)]
+#[require(GpuCullCompute)]
+pub struct GpuStableCompaction;
+
+/// Sort the visible instances of every view and LOD level back to front in the GPU cull pass.
+///
+/// Meant for entities with [`InstancedAlphaMode::Blend`](crate::material::InstancedAlphaMode::Blend).
+/// The instances are sorted by distance to the camera with a bitonic sort after the stable
+/// compaction, which runs in a single workgroup per entity and gets expensive for large counts.
+#[derive(Component, Clone, Copy, Default, ExtractComponent)]
+#[require(GpuStableCompaction)]
+pub struct GpuInstanceSort;
+
+/// Bounding sphere of a single instance in mesh space, used for frustum culling in the
+/// GPU cull pass.
+///
+/// The sphere is scaled by the instance scale and the entity's `GlobalTransform`.
+/// Defaults to the bounding sphere of the mesh's `Aabb`, see
+/// [`MeshBoundingSpheres`](crate::resources::MeshBoundingSpheres).
+#[derive(Component, Clone, Copy, Debug, Reflect, ExtractComponent)]
+#[reflect(Component, Clone, Debug)]
+pub struct InstanceBoundingSphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Default for InstanceBoundingSphere {
+    fn default() -> Self {
+        Self {
+            center: Vec3::ZERO,
+            radius: 1.0,
+        }
+    }
+}
+
+impl From<InstanceBoundingSphere> for Vec4 {
+    fn from(value: InstanceBoundingSphere) -> Self {
+        Vec4::from((value.center, value.radius))
+    }
+}
+
+/// Stochastically thins out GPU culled instances with camera distance.
+///
+/// The density falls linearly from `1.0` at `start` to `end_density` at `end`. Which instances
+/// survive is decided by a hash of `InstanceData::index`, so they stay stable while the camera
+/// moves.
+#[derive(Component, Clone, Copy, Debug, Reflect, ExtractComponent)]
+#[reflect(Component, Clone, Debug)]
+#[require(GpuCullCompute)]
+pub struct InstanceDensityFalloff {
+    pub start: f32,
+    pub end: f32,
+    /// Fraction of instances kept at and beyond `end`.
+    pub end_density: f32,
+    /// Scale up the surviving instances to cover the area of the dropped ones, up to twice
+    /// their original scale.
+    pub compensate_scale: bool,
+}
+
+impl From<InstanceDensityFalloff> for Vec4 {
+    fn from(value: InstanceDensityFalloff) -> Self {
+        Vec4::new(value.start, value.end, value.end_density, 0.0)
+    }
+}
+
+/// Culls GPU culled instances whose projected bounding sphere is smaller than this many pixels
+/// in diameter.
+///
+/// The size is estimated from the [`InstanceBoundingSphere`] scaled by the instance, the camera
+/// distance and the projection of each view, so it adapts to zoom and resolution changes.
+#[derive(Component, Clone, Copy, Debug, Default, Reflect, ExtractComponent)]
+#[reflect(Component, Clone, Debug)]
+#[require(GpuCullCompute)]
+pub struct InstanceMinScreenSize(pub f32);
+
+/// Maximum number of [`InstanceLods`] levels, in addition to the entity's mesh.
+pub const MAX_INSTANCE_LODS: usize = 4;
+
+/// Additional level of detail meshes for GPU culled instances.
+///
+/// The entity's `Mesh3d` is LOD 0, every level replaces the previous one from its `distance`
+/// onwards. The cull pass sorts each instance into the indirect draw of its level.
+///
+/// Levels have to be sorted by distance and share the vertex layout and indexing of the entity's
+/// mesh. At most [`MAX_INSTANCE_LODS`] levels are used.
+#[derive(Component, Clone, Debug, Default, Reflect, ExtractComponent)]
+#[reflect(Component, Clone, Debug)]
+#[require(GpuCullCompute)]
+pub struct InstanceLods(pub Vec<InstanceLod>);
+
+#[derive(Clone, Debug, Reflect)]
+pub struct InstanceLod {
+    pub mesh: Handle<Mesh>,
+    /// Camera distance from which this level is used.
+    pub distance: f32,
+}
+
+/// Shadow casting settings of an instanced entity, applied in every shadow view.
+///
+/// Shadows are cast by all instances, regardless of the GPU cull pass of the camera. Entities
+/// without this component cast shadows of all instances with their own mesh, `NotShadowCaster`
+/// disables them entirely.
+#[derive(Component, Clone, Copy, Debug, Reflect, ExtractComponent)]
+#[reflect(Component, Clone, Debug)]
+pub struct InstanceShadowSettings {
+    /// Fraction of instances casting shadows. Which instances are kept is decided by a hash of
+    /// `InstanceData::index`, like [`InstanceDensityFalloff`].
+    pub density: f32,
+    /// Draws the shadows with this level of the entity's [`InstanceLods`], `0` being the
+    /// entity's mesh. Clamped to the last level.
+    pub lod: Option<usize>,
+}
+
+impl Default for InstanceShadowSettings {
+    fn default() -> Self {
+        Self {
+            density: 1.0,
+            lod: None,
+        }
+    }
+}
+
+impl InstanceShadowSettings {
+    /// The mesh drawn in shadow views instead of `mesh`.
+    pub fn mesh(&self, mesh: AssetId<Mesh>, lods: Option<&InstanceLods>) -> AssetId<Mesh> {
+        match (self.lod, lods) {
+            (Some(level), Some(lods)) if level > 0 && !lods.0.is_empty() => {
+                lods.0[(level - 1).min(lods.0.len() - 1)].mesh.id()
+            }
+            _ => mesh,
+        }
+    }
+}
+
+/// Keep the instances of the previous frame for the motion vectors of animated instances.
+///
+/// Without it only the movement of the entity's `GlobalTransform` is picked up. The previous
+/// instance is looked up by `InstanceData::index`, which has to be the position of the instance
+/// in `InstanceMaterialData::instances`.
+#[derive(Component, Clone, Copy, Debug, Default, Reflect, ExtractComponent)]
+#[reflect(Component, Clone, Debug)]
+pub struct InstancePreviousData;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct InstanceData {
     pub position: Vec3,
@@ -37,14 +205,406 @@ pub struct InstanceData {
 
     pub rotation: f32,
     pub index: u32,
-    pub _padding: [u32; 2],
+    /// Linear RGBA color of the instance, multiplied with `instance_uniforms.color`.
+    ///
+    /// Available to shaders as `Vertex::i_color`. Defaults to white, see
+    /// [`InstanceData::with_color`].
+    pub color: [u8; 4],
+    /// Index of the entity's uniforms in batched draws, available to shaders as
+    /// `Vertex::i_uniform_index`.
+    ///
+    /// Not meant to be set by users, the
+    /// [`GpuCullMode::Arena`](crate::resources::GpuCullMode::Arena) overwrites it with the
+    /// [`PreparedInstanceUniforms::index`] of the entity and other paths ignore it.
+    pub uniform_index: u32,
+}
+
+impl Default for InstanceData {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            scale: 0.0,
+            rotation: 0.0,
+            index: 0,
+            color: [u8::MAX; 4],
+            uniform_index: 0,
+        }
+    }
+}
+
+impl InstanceData {
+    pub fn with_color(mut self, color: impl Into<LinearRgba>) -> Self {
+        self.color = color.into().to_u8_array();
+        self
+    }
+}
+
+/// Instance with a full rotation and a non-uniform scale, see [`InstanceBase::Oriented`].
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct OrientedInstanceData {
+    pub position: Vec3,
+    pub index: u32,
+    pub rotation: Quat,
+    pub scale: Vec3,
+    /// See [`InstanceData::color`].
+    pub color: [u8; 4],
+}
+
+impl Default for OrientedInstanceData {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            index: 0,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            color: [u8::MAX; 4],
+        }
+    }
+}
+
+impl OrientedInstanceData {
+    pub fn from_transform(transform: Transform) -> Self {
+        Self {
+            position: transform.translation,
+            rotation: transform.rotation,
+            scale: transform.scale,
+            ..default()
+        }
+    }
+
+    pub fn with_color(mut self, color: impl Into<LinearRgba>) -> Self {
+        self.color = color.into().to_u8_array();
+        self
+    }
+}
+
+/// Instance struct of an [`InstanceMaterialData`], uploaded as is to the instance vertex buffer
+/// and the buffers of the GPU cull pass.
+///
+/// Custom instances start with the fields of their [`InstanceBase`], e.g. by embedding an
+/// [`InstanceData`] as the first field, and declare the vertex attributes of the remaining ones.
+///
+/// ```ignore
+/// #[derive(Clone, Copy, Pod, Zeroable)]
+/// #[repr(C)]
+/// struct WindInstance {
+///     instance: InstanceData,
+///     stiffness: f32,
+///     phase: f32,
+///     _padding: [f32; 2],
+/// }
+///
+/// impl InstanceType for WindInstance {
+///     const LAYOUT: InstanceLayout = InstanceLayout::new(
+///         InstanceBase::Compact,
+///         size_of::<Self>(),
+///         &[
+///             VertexAttribute {
+///                 format: VertexFormat::Float32x2,
+///                 offset: offset_of!(WindInstance, stiffness) as u64,
+///                 shader_location: 13,
+///             },
+///         ],
+///     );
+/// }
+/// ```
+pub trait InstanceType: Pod + Send + Sync + 'static {
+    const LAYOUT: InstanceLayout;
+}
+
+impl InstanceType for InstanceData {
+    const LAYOUT: InstanceLayout = InstanceLayout::COMPACT;
+}
+
+impl InstanceType for OrientedInstanceData {
+    const LAYOUT: InstanceLayout = InstanceLayout::ORIENTED;
+}
+
+/// Leading fields of an [`InstanceType`], read by the built-in shaders and the GPU cull pass.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum InstanceBase {
+    /// [`InstanceData`], a uniform scale and a rotation around the Y axis.
+    #[default]
+    Compact,
+    /// [`OrientedInstanceData`], a quaternion and a `Vec3` scale. Sets the `ORIENTED_INSTANCES`
+    /// shader def.
+    Oriented,
+}
+
+impl InstanceBase {
+    /// Size of the base fields in bytes.
+    pub const fn size(self) -> usize {
+        match self {
+            InstanceBase::Compact => size_of::<InstanceData>(),
+            InstanceBase::Oriented => size_of::<OrientedInstanceData>(),
+        }
+    }
+}
+
+/// Memory layout of an [`InstanceType`].
+///
+/// Selects the instance vertex attributes and the shader defs of the render and cull pipelines.
+/// The fields past the base are declared to the shaders as `INSTANCE_EXTRA_WORDS` words, which
+/// are copied by the GPU cull pass as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceLayout {
+    pub base: InstanceBase,
+    /// Size of a single instance in bytes.
+    pub size: u64,
+    /// Vertex attributes of the fields past the base, starting at location 13.
+    pub attributes: &'static [VertexAttribute],
+}
+
+impl Default for InstanceLayout {
+    fn default() -> Self {
+        InstanceLayout::COMPACT
+    }
+}
+
+impl InstanceLayout {
+    pub const COMPACT: Self = Self::new(InstanceBase::Compact, size_of::<InstanceData>(), &[]);
+
+    pub const ORIENTED: Self = Self::new(
+        InstanceBase::Oriented,
+        size_of::<OrientedInstanceData>(),
+        &[],
+    );
+
+    /// Panics if the instance is smaller than its base or its size isn't a multiple of 16 bytes,
+    /// the stride of the instance arrays in WGSL.
+    pub const fn new(
+        base: InstanceBase,
+        size: usize,
+        attributes: &'static [VertexAttribute],
+    ) -> Self {
+        assert!(
+            size >= base.size() && size.is_multiple_of(16),
+            "instances have to start with their base and be a multiple of 16 bytes"
+        );
+
+        Self {
+            base,
+            size: size as u64,
+            attributes,
+        }
+    }
+
+    /// Number of 4 byte words past the base.
+    pub fn extra_words(&self) -> u32 {
+        ((self.size - self.base.size() as u64) / 4) as u32
+    }
+
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![];
+        if self.base == InstanceBase::Oriented {
+            shader_defs.push("ORIENTED_INSTANCES".into());
+        }
+        if self.extra_words() > 0 {
+            shader_defs.push(ShaderDefVal::UInt(
+                "INSTANCE_EXTRA_WORDS".into(),
+                self.extra_words(),
+            ));
+        }
+        shader_defs
+    }
+}
+
+trait InstanceSlice: Send + Sync {
+    fn len(&self) -> usize;
+
+    fn bytes(&self) -> &[u8];
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn to_arc(&self) -> Arc<dyn InstanceSlice>;
+}
+
+impl<I: InstanceType> InstanceSlice for Vec<I> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(self.as_slice())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn to_arc(&self) -> Arc<dyn InstanceSlice> {
+        Arc::new(self.clone())
+    }
+}
+
+/// Maximum number of [`InstanceChanges::Ranges`], further edits merge them into one.
+pub const MAX_INSTANCE_CHANGE_RANGES: usize = 16;
+
+/// Instances changed since the last extraction, only these are uploaded to the GPU.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum InstanceChanges {
+    /// New instances, e.g. after assigning [`InstanceMaterialData::instances`].
+    #[default]
+    All,
+    /// Sorted, non-overlapping ranges of changed instances, empty if nothing changed.
+    Ranges(Vec<Range<usize>>),
+}
+
+impl InstanceChanges {
+    /// Whether nothing changed and no upload is needed.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, InstanceChanges::Ranges(ranges) if ranges.is_empty())
+    }
+
+    fn insert(&mut self, range: Range<usize>) {
+        let InstanceChanges::Ranges(ranges) = self else {
+            return;
+        };
+        if range.is_empty() {
+            return;
+        }
+
+        // Merge with all overlapping or adjacent ranges.
+        let start = ranges.partition_point(|changed| changed.end < range.start);
+        let end = ranges.partition_point(|changed| changed.start <= range.end);
+        let merged = ranges[start..end].iter().fold(range, |merged, changed| {
+            merged.start.min(changed.start)..merged.end.max(changed.end)
+        });
+        ranges.splice(start..end, [merged]);
+
+        if ranges.len() > MAX_INSTANCE_CHANGE_RANGES {
+            let merged = ranges[0].start..ranges[ranges.len() - 1].end;
+            *ranges = vec![merged];
+        }
+    }
+}
+
+/// Instances of an [`InstanceMaterialData`] of any [`InstanceType`].
+///
+/// Edits through [`Instances::edit`] and [`Instances::edit_all`] are recorded in
+/// [`Instances::changes`], so the render world only uploads the changed instances.
+#[derive(Clone)]
+pub struct Instances {
+    layout: InstanceLayout,
+    instances: Arc<dyn InstanceSlice>,
+    changes: InstanceChanges,
+}
+
+impl Default for Instances {
+    fn default() -> Self {
+        Vec::<InstanceData>::new().into()
+    }
+}
+
+impl Instances {
+    pub fn new<I: InstanceType>(instances: Arc<Vec<I>>) -> Self {
+        Self {
+            layout: I::LAYOUT,
+            instances,
+            changes: InstanceChanges::All,
+        }
+    }
+
+    pub fn layout(&self) -> InstanceLayout {
+        self.layout
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The instances as uploaded to the instance buffers.
+    pub fn bytes(&self) -> &[u8] {
+        self.instances.bytes()
+    }
+
+    /// The instances, if they are of type `I`.
+    pub fn get<I: InstanceType>(&self) -> Option<&[I]> {
+        self.instances
+            .as_any()
+            .downcast_ref::<Vec<I>>()
+            .map(Vec::as_slice)
+    }
+
+    /// Edits a range of the instances, if they are of type `I`, and marks it as changed.
+    ///
+    /// The instances are cloned if they are shared, e.g. with the render world.
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of bounds.
+    pub fn edit<I: InstanceType>(&mut self, range: Range<usize>) -> Option<&mut [I]> {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "instance range {range:?} out of bounds for {} instances",
+            self.len()
+        );
+        self.get::<I>()?;
+        self.changes.insert(range.clone());
+        self.get_mut::<I>().map(|instances| &mut instances[range])
+    }
+
+    /// Edits all instances, if they are of type `I`, and marks them as changed. Unlike
+    /// [`Instances::edit`], instances can be added and removed.
+    pub fn edit_all<I: InstanceType>(&mut self) -> Option<&mut Vec<I>> {
+        self.get::<I>()?;
+        self.changes = InstanceChanges::All;
+        self.get_mut::<I>()
+    }
+
+    /// Instances changed since the last extraction.
+    pub fn changes(&self) -> &InstanceChanges {
+        &self.changes
+    }
+
+    pub(crate) fn clear_changes(&mut self) {
+        self.changes = InstanceChanges::Ranges(Vec::new());
+    }
+
+    pub(crate) fn mark_all_changed(&mut self) {
+        self.changes = InstanceChanges::All;
+    }
+
+    fn get_mut<I: InstanceType>(&mut self) -> Option<&mut Vec<I>> {
+        self.get::<I>()?;
+        if Arc::get_mut(&mut self.instances).is_none() {
+            self.instances = self.instances.to_arc();
+        }
+
+        Arc::get_mut(&mut self.instances)?
+            .as_any_mut()
+            .downcast_mut::<Vec<I>>()
+    }
+}
+
+impl<I: InstanceType> From<Vec<I>> for Instances {
+    fn from(instances: Vec<I>) -> Self {
+        Instances::new(Arc::new(instances))
+    }
+}
+
+impl<I: InstanceType> From<Arc<Vec<I>>> for Instances {
+    fn from(instances: Arc<Vec<I>>) -> Self {
+        Instances::new(instances)
+    }
 }
 
 #[derive(Component, Clone, Reflect)]
 #[reflect(Component, Clone, Debug)]
 pub struct InstanceMaterialData {
     #[reflect(ignore)]
-    pub instances: Arc<Vec<InstanceData>>,
+    pub instances: Instances,
     pub color: LinearRgba,
     pub visibility_range: Vec4,
 }
@@ -59,41 +619,141 @@ impl fmt::Debug for InstanceMaterialData {
     }
 }
 
-impl ExtractComponent for InstanceMaterialData {
-    type QueryData = (&'static Self, &'static GlobalTransform);
-    type QueryFilter = ();
-    type Out = (Self, GlobalTransform);
+/// Extracts the [`InstanceMaterialData`] and [`GlobalTransform`] of changed entities.
+///
+/// Render entities are respawned empty when a synced component of their main entity is removed,
+/// they are refilled from the [`ExtractedInstanceMaterialData`] cache.
+pub fn extract_instance_material_data(
+    mut commands: Commands,
+    mut cache: ResMut<ExtractedInstanceMaterialData>,
+    query: Extract<
+        Query<(
+            Entity,
+            RenderEntity,
+            Ref<InstanceMaterialData>,
+            Ref<GlobalTransform>,
+        )>,
+    >,
+    mut removed: Extract<RemovedComponents<InstanceMaterialData>>,
+    extracted: Query<(), With<InstanceMaterialData>>,
+) {
+    for entity in removed.read() {
+        cache.remove(&MainEntity::from(entity));
+    }
+
+    let mut values = Vec::new();
+    for (entity, render_entity, data, transform) in &query {
+        if data.is_changed() || transform.is_changed() {
+            let mut cached = data.clone();
+            // Respawned render entities lost their instance buffers.
+            cached.instances.mark_all_changed();
+            cache.insert(entity.into(), (cached, *transform));
+            values.push((render_entity, (data.clone(), *transform)));
+        } else if !extracted.contains(render_entity)
+            && let Some(cached) = cache.get(&MainEntity::from(entity))
+        {
+            values.push((render_entity, cached.clone()));
+        }
+    }
+    commands.try_insert_batch(values);
+}
 
-    fn extract_component(
-        (data, transform): QueryItem<'_, '_, Self::QueryData>,
-    ) -> Option<Self::Out> {
-        Some((data.clone(), *transform))
+/// Resets the [`InstanceChanges`] after they were extracted to the render world.
+pub fn clear_instance_changes(
+    mut query: Query<&mut InstanceMaterialData, Changed<InstanceMaterialData>>,
+) {
+    for mut instance_data in &mut query {
+        // Keeps the change ticks of the edits.
+        instance_data
+            .bypass_change_detection()
+            .instances
+            .clear_changes();
     }
 }
 
+/// Number of instances to allocate instance buffers for.
+///
+/// Buffers grow geometrically and are reused as long as the instances fit, so adding instances
+/// doesn't reallocate them every frame.
+pub fn instance_capacity(length: usize) -> usize {
+    length.next_power_of_two()
+}
+
 #[derive(Component)]
 pub struct InstanceBuffer {
     pub buffer: Buffer,
+    /// Number of instances drawn.
     pub length: usize,
+    /// Number of instances the buffer has room for, see [`instance_capacity`].
+    pub capacity: usize,
+    /// Byte offset between the instances of consecutive views, `0` if all views share them.
+    pub view_stride: u64,
+    /// Byte offset between the instances of consecutive LOD levels within a view.
+    pub lod_stride: u64,
 }
 
 #[derive(Component)]
 pub struct GpuDrawIndexedIndirect {
     pub buffer: Buffer,
     pub offset: u64,
+    /// Byte offset between the draw arguments of consecutive views, `0` if all views share them.
+    pub view_stride: u64,
+    /// Byte offset between the draw arguments of consecutive LOD levels within a view.
+    pub lod_stride: u64,
+}
+
+/// Indirect draw arguments of GPU culled entities with a non-indexed mesh.
+#[derive(Component)]
+pub struct GpuDrawIndirect {
+    pub buffer: Buffer,
+    pub offset: u64,
+    /// Byte offset between the draw arguments of consecutive views.
+    pub view_stride: u64,
+    /// Byte offset between the draw arguments of consecutive LOD levels within a view.
+    pub lod_stride: u64,
 }
 
+/// Meshes of every LOD level of a GPU culled entity, starting with LOD 0.
+#[derive(Component, Clone, Debug, Deref, DerefMut)]
+pub struct InstanceLodMeshes(pub Vec<AssetId<Mesh>>);
+
 #[derive(Component)]
 pub struct InstanceLodBuffer {
     pub buffer: Buffer,
 }
 
+/// Hierarchical-Z buffer of a view, used for occlusion culling in the GPU cull pass.
+///
+/// Mip 0 is half the resolution of the depth prepass, every texel stores the farthest depth
+/// of the texels it covers.
+#[derive(Component)]
+pub struct InstancedDepthPyramid {
+    pub texture: Texture,
+    /// A view containing all mips, bound in the cull pass.
+    pub all_mips: TextureView,
+    /// One view per mip, used while building the pyramid.
+    pub mips: Vec<TextureView>,
+    /// Size of the depth buffer the pyramid was allocated for.
+    pub depth_size: UVec2,
+    /// `clip_from_world` of the frame the pyramid was last built in.
+    pub clip_from_world: Mat4,
+    /// Viewport (x, y, width, height) of the frame the pyramid was last built in.
+    pub viewport: Vec4,
+}
+
 #[derive(Clone, Copy, Pod, Zeroable, Default)]
 #[repr(C)]
 pub struct InstanceUniforms {
     pub color: LinearRgba,
     pub visibility_range: Vec4,
     pub world_from_local: Mat4,
+    /// `world_from_local` of the previous frame, used for motion vectors.
+    pub previous_world_from_local: Mat4,
+    /// See [`InstanceShadowSettings::density`].
+    pub shadow_density: f32,
+    /// See [`InstancedAlphaMode::Mask`](crate::material::InstancedAlphaMode::Mask).
+    pub alpha_cutoff: f32,
+    pub _padding: [f32; 2],
 }
 
 impl From<&InstanceMaterialData> for InstanceUniforms {
@@ -101,23 +761,85 @@ impl From<&InstanceMaterialData> for InstanceUniforms {
         InstanceUniforms {
             color: value.color,
             visibility_range: value.visibility_range,
+            shadow_density: 1.0,
             ..default()
         }
     }
 }
 
+/// Material bindings and instance uniforms, shared by all entities of a material.
+#[derive(Component, Clone)]
+pub struct InstancedCombinedBindGroup {
+    /// Binds the uniforms of a single entity with its
+    /// [`PreparedInstanceUniforms::offset`].
+    pub bind_group: BindGroup,
+    /// Binds the uniforms of all entities for batched draws of the
+    /// [`GpuCullMode::Arena`](crate::resources::GpuCullMode::Arena), `None` if the device
+    /// doesn't support them.
+    pub batched_bind_group: Option<BindGroup>,
+}
+
+/// The [`InstanceUniforms`] of an entity in the
+/// [`InstanceUniformsBuffer`](crate::resources::InstanceUniformsBuffer) of its material.
 #[derive(Component)]
-pub struct InstancedCombinedBindGroup(pub BindGroup);
+pub struct PreparedInstanceUniforms {
+    /// Its `world_from_local` becomes `previous_world_from_local` in the next frame.
+    pub uniforms: InstanceUniforms,
+    /// Dynamic offset of the uniforms in the buffer.
+    pub offset: u32,
+    /// Index of the uniforms in the buffer, selected per instance in batched draws.
+    pub index: u32,
+    /// Only entities of the same material can be batched.
+    pub material: UntypedAssetId,
+    /// Blended entities are sorted back to front one by one, so they aren't batched.
+    pub blend: bool,
+}
 
+/// Instances of the previous frame of an entity with [`InstancePreviousData`], bound in the
+/// camera prepasses.
 #[derive(Component)]
-pub struct InstanceUniformBuffer {
+pub struct PreviousInstanceBuffer {
     pub buffer: Buffer,
+    pub bind_group: BindGroup,
+    /// Number of instances bound, the previous instance count.
+    pub length: usize,
+    /// Number of instances the buffer has room for, see [`instance_capacity`].
+    pub capacity: usize,
+    /// The current instances, uploaded in the next frame.
+    pub instances: Instances,
 }
 
 #[derive(Component)]
 pub struct InstancedComputeSourceBuffer {
     pub buffer: Buffer,
     pub count: u32,
+    /// Number of instances the source, output and visibility buffers have room for, see
+    /// [`instance_capacity`].
+    pub capacity: u32,
+    /// Visibility of the instances for stable compaction.
+    pub visibility: Option<Buffer>,
+    /// Number of views the output and indirect buffers were allocated for.
+    pub view_count: u32,
+    /// Number of LOD levels the output and indirect buffers were allocated for.
+    pub lod_count: u32,
+    /// Whether the visible instances are compacted in source order.
+    pub stable_compaction: bool,
+    pub layout: InstanceLayout,
+    /// The instances changed in a frame without cull views, whose [`InstanceChanges`] were
+    /// never uploaded. The buffer is uploaded as a whole when culling resumes.
+    pub stale: bool,
+    /// Draw arguments of all views and LOD levels with an `instance_count` of `0`, written
+    /// every frame to reset the counts of the previous frame.
+    pub indirect_contents: Vec<u8>,
+}
+
+/// Camera data of a view for the GPU cull pass, bound at group 1.
+#[derive(Component)]
+pub struct ViewCullBuffer {
+    pub buffer: Buffer,
+    pub bind_group: BindGroup,
+    /// Index of the view's instances and draw arguments in the per-view output buffers.
+    pub view_index: u32,
 }
 
 #[derive(Component)]
@@ -139,3 +861,137 @@ where
         Some(item.clone())
     }
 }
+
+#[cfg(test)]
+// Expected changes are written as arrays of ranges.
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    fn instances(count: u32) -> Instances {
+        (0..count)
+            .map(|index| InstanceData { index, ..default() })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn cleared(count: u32) -> Instances {
+        let mut instances = instances(count);
+        instances.clear_changes();
+        instances
+    }
+
+    fn ranges(instances: &Instances) -> &[Range<usize>] {
+        match instances.changes() {
+            InstanceChanges::Ranges(ranges) => ranges,
+            InstanceChanges::All => panic!("expected ranges, got `All`"),
+        }
+    }
+
+    #[test]
+    fn new_instances_change_all() {
+        let instances = instances(4);
+        assert!(matches!(instances.changes(), InstanceChanges::All));
+        assert!(!instances.changes().is_empty());
+    }
+
+    #[test]
+    fn cleared_changes_are_empty() {
+        let instances = cleared(4);
+        assert!(instances.changes().is_empty());
+        assert!(ranges(&instances).is_empty());
+    }
+
+    #[test]
+    fn disjoint_ranges_stay_sorted() {
+        let mut instances = cleared(100);
+        instances.edit::<InstanceData>(50..60);
+        instances.edit::<InstanceData>(10..20);
+        instances.edit::<InstanceData>(30..40);
+        assert_eq!(ranges(&instances), [10..20, 30..40, 50..60]);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_merge() {
+        let mut instances = cleared(100);
+        instances.edit::<InstanceData>(10..20);
+        instances.edit::<InstanceData>(15..25);
+        assert_eq!(ranges(&instances), [10..25]);
+
+        instances.edit::<InstanceData>(25..30);
+        instances.edit::<InstanceData>(5..10);
+        assert_eq!(ranges(&instances), [5..30]);
+
+        instances.edit::<InstanceData>(40..50);
+        instances.edit::<InstanceData>(60..70);
+        instances.edit::<InstanceData>(0..65);
+        assert_eq!(ranges(&instances), [0..70]);
+    }
+
+    #[test]
+    fn empty_ranges_are_ignored() {
+        let mut instances = cleared(10);
+        instances.edit::<InstanceData>(5..5);
+        assert!(instances.changes().is_empty());
+    }
+
+    #[test]
+    fn too_many_ranges_collapse() {
+        let mut instances = cleared(100);
+        for index in 0..MAX_INSTANCE_CHANGE_RANGES {
+            instances.edit::<InstanceData>(index * 4..index * 4 + 1);
+        }
+        assert_eq!(ranges(&instances).len(), MAX_INSTANCE_CHANGE_RANGES);
+
+        instances.edit::<InstanceData>(90..91);
+        assert_eq!(ranges(&instances), [0..91]);
+    }
+
+    #[test]
+    fn edits_after_all_stay_all() {
+        let mut instances = instances(10);
+        instances.edit::<InstanceData>(2..3);
+        assert!(matches!(instances.changes(), InstanceChanges::All));
+    }
+
+    #[test]
+    fn edits_after_growth() {
+        let mut instances = cleared(4);
+        instances
+            .edit_all::<InstanceData>()
+            .unwrap()
+            .extend([InstanceData::default(); 4]);
+        assert!(matches!(instances.changes(), InstanceChanges::All));
+        assert_eq!(instances.len(), 8);
+
+        instances.clear_changes();
+        instances.edit::<InstanceData>(6..8).unwrap()[0].index = 42;
+        assert_eq!(ranges(&instances), [6..8]);
+        assert_eq!(instances.get::<InstanceData>().unwrap()[6].index, 42);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds_edits_panic() {
+        let mut instances = cleared(4);
+        instances.edit::<InstanceData>(2..5);
+    }
+
+    #[test]
+    fn mismatched_types_change_nothing() {
+        let mut instances = cleared(4);
+        assert!(instances.edit::<OrientedInstanceData>(0..2).is_none());
+        assert!(instances.edit_all::<OrientedInstanceData>().is_none());
+        assert!(instances.changes().is_empty());
+    }
+
+    #[test]
+    fn shared_instances_are_cloned_on_edit() {
+        let mut instances = cleared(4);
+        let shared = instances.clone();
+        instances.edit::<InstanceData>(0..1).unwrap()[0].index = 42;
+
+        assert_eq!(instances.get::<InstanceData>().unwrap()[0].index, 42);
+        assert_eq!(shared.get::<InstanceData>().unwrap()[0].index, 0);
+    }
+}
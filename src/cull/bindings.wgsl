@@ -1,10 +1,25 @@
 #define_import_path bevy_eidolon::cull::bindings
 
-#import bevy_eidolon::cull::types::{InstanceData, DrawIndexedIndirectArgs, LodCullData, CameraCullData}
+#import bevy_eidolon::cull::types::{InstanceData, DrawIndexedIndirectArgs, DrawIndirectArgs, LodCullData, CameraCullData, ArenaChunkData, ArenaCullData, InstanceVisibility}
 
 @group(0) @binding(0) var<storage, read> source_buffer: array<InstanceData>;
 @group(0) @binding(1) var<storage, read_write> instance_buffer: array<InstanceData>;
-@group(0) @binding(2) var<storage, read_write> indirect_args: DrawIndexedIndirectArgs;
+#ifdef NON_INDEXED
+@group(0) @binding(2) var<storage, read_write> indirect_args: array<DrawIndirectArgs>;
+#else
+@group(0) @binding(2) var<storage, read_write> indirect_args: array<DrawIndexedIndirectArgs>;
+#endif
+#ifdef ARENA
+@group(0) @binding(3) var<storage, read> chunks: array<ArenaChunkData>;
+@group(0) @binding(4) var<uniform> arena: ArenaCullData;
+@group(0) @binding(5) var<storage, read_write> non_indexed_indirect_args: array<DrawIndirectArgs>;
+@group(0) @binding(6) var<storage, read_write> visibility: array<InstanceVisibility>;
+// Set to the chunk of the current instance.
+var<private> lod_data: LodCullData;
+#else
 @group(0) @binding(3) var<uniform> lod_data: LodCullData;
+@group(0) @binding(4) var<storage, read_write> visibility: array<InstanceVisibility>;
+#endif
 
 @group(1) @binding(0) var<uniform> camera: CameraCullData;
+@group(1) @binding(1) var depth_pyramid: texture_2d<f32>;
@@ -1,9 +1,27 @@
-#import bevy_pbr::utils::rand_f
-#import bevy_eidolon::cull::bindings::{source_buffer, instance_buffer, indirect_args, lod_data, camera}
-
-fn hash_noise(index: u32) -> f32 {
-    var state = index;
-    return rand_f(&state);
+#import bevy_eidolon::render::utils::hash_noise
+#import bevy_eidolon::cull::bindings::{source_buffer, lod_data, camera}
+#import bevy_eidolon::cull::functions::{
+    COMPACT_WORKGROUP_SIZE,
+    compact_instances,
+    falloff_density,
+    instance_world_matrix,
+    is_sphere_in_frustum,
+    is_sphere_occluded,
+    load_chunk,
+    max_axis_scale,
+    projected_diameter,
+    reset_visibility,
+    scale_instance,
+    select_lod,
+    write_instance,
+}
+#ifdef ARENA
+#import bevy_eidolon::cull::functions::find_chunk
+#endif
+#import bevy_eidolon::cull::types::{
+    INSTANCE_CULL_FLAGS_OCCLUSION,
+    INSTANCE_CULL_FLAGS_DENSITY_FALLOFF,
+    INSTANCE_CULL_FLAGS_DENSITY_SCALE_COMPENSATION,
 }
 
 @compute @workgroup_size(64)
@@ -11,9 +29,18 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let i = global_id.x;
     if (i >= arrayLength(&source_buffer)) { return; }
 
-    let instance = source_buffer[i];
-    let local_pos = vec4<f32>(instance.pos_and_scale.xyz, 1.0);
-    let world_pos = lod_data.world_from_local * local_pos;
+#ifdef ARENA
+    let range = load_chunk(find_chunk(i));
+#else
+    let range = load_chunk();
+#endif
+
+    reset_visibility(i);
+
+    var instance = source_buffer[i];
+    var world_from_instance = instance_world_matrix(instance);
+    var scale_factor = 1.0;
+    let world_pos = world_from_instance[3];
 
     let dist = distance(world_pos.xyz, camera.view_pos.xyz);
 
@@ -21,7 +48,53 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         return;
     }
 
-    let write_index = atomicAdd(&indirect_args.instance_count, 1u);
+    if ((lod_data.flags & INSTANCE_CULL_FLAGS_DENSITY_FALLOFF) != 0u) {
+        let density = falloff_density(dist);
+
+        // Hashing the stable instance index keeps the same survivors from frame to frame.
+        if (hash_noise(instance.index) >= density) {
+            return;
+        }
+
+        if ((lod_data.flags & INSTANCE_CULL_FLAGS_DENSITY_SCALE_COMPENSATION) != 0u) {
+            // Keep the covered area roughly constant, capped at twice the original scale.
+            scale_factor = inverseSqrt(max(density, 0.25));
+            instance = scale_instance(instance, scale_factor);
+            world_from_instance = instance_world_matrix(instance);
+        }
+    }
+
+    let sphere_center = (world_from_instance * vec4<f32>(lod_data.bounding_sphere.xyz, 1.0)).xyz;
+    let sphere_radius = lod_data.bounding_sphere.w * max_axis_scale(world_from_instance);
+
+    if (lod_data.min_screen_size > 0.0
+        && projected_diameter(sphere_center, sphere_radius) < lod_data.min_screen_size) {
+        return;
+    }
+
+    if (!is_sphere_in_frustum(sphere_center, sphere_radius)) {
+        return;
+    }
+
+    if ((lod_data.flags & INSTANCE_CULL_FLAGS_OCCLUSION) != 0u
+        && is_sphere_occluded(sphere_center, sphere_radius)) {
+        return;
+    }
+
+    write_instance(i, range, select_lod(dist), instance, scale_factor);
+}
+
+// One workgroup per entity or arena chunk.
+@compute @workgroup_size(COMPACT_WORKGROUP_SIZE)
+fn compact(
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+#ifdef ARENA
+    let range = load_chunk(workgroup_id.x);
+#else
+    let range = load_chunk();
+#endif
 
-    instance_buffer[write_index] = instance;
+    compact_instances(local_index, range);
 }
@@ -0,0 +1,38 @@
+// Builds one mip of the depth pyramid, every texel stores the farthest (reverse-Z: smallest)
+// depth of the 2x2 texels it covers in the previous mip or the depth prepass.
+
+#ifdef FIRST_MIP
+#ifdef MULTISAMPLED
+@group(0) @binding(0) var input: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0) var input: texture_depth_2d;
+#endif
+#else
+@group(0) @binding(0) var input: texture_2d<f32>;
+#endif
+
+@group(0) @binding(1) var output: texture_storage_2d<r32float, write>;
+
+fn load_depth(coord: vec2<i32>) -> f32 {
+#ifdef FIRST_MIP
+    // Mip level for single sampled textures, sample index for multisampled textures.
+    return textureLoad(input, coord, 0);
+#else
+    return textureLoad(input, coord, 0).r;
+#endif
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if (any(global_id.xy >= textureDimensions(output))) { return; }
+
+    let max_coord = vec2<i32>(textureDimensions(input)) - 1;
+    let coord = vec2<i32>(global_id.xy) * 2;
+
+    let depth = min(
+        min(load_depth(min(coord, max_coord)), load_depth(min(coord + vec2(1, 0), max_coord))),
+        min(load_depth(min(coord + vec2(0, 1), max_coord)), load_depth(min(coord + vec2(1, 1), max_coord)))
+    );
+
+    textureStore(output, global_id.xy, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
@@ -0,0 +1,339 @@
+#define_import_path bevy_eidolon::cull::functions
+
+#import bevy_eidolon::render::utils::{calculate_instance_world_matrix, calculate_oriented_instance_world_matrix}
+#import bevy_eidolon::cull::bindings::{source_buffer, instance_buffer, indirect_args, lod_data, visibility, camera, depth_pyramid}
+#ifdef ARENA
+#import bevy_eidolon::cull::bindings::{chunks, arena, non_indexed_indirect_args}
+#endif
+#import bevy_eidolon::cull::types::{
+    INSTANCE_CULL_FLAGS_NON_INDEXED,
+    INSTANCE_CULL_FLAGS_SORT,
+    INSTANCE_CULL_FLAGS_STABLE_COMPACTION,
+    INSTANCE_CULLED,
+    InstanceData,
+    InstanceVisibility,
+}
+
+// Fraction of instances kept at `dist`, see `InstanceDensityFalloff`.
+fn falloff_density(dist: f32) -> f32 {
+    let falloff = lod_data.density_falloff;
+    let t = saturate((dist - falloff.x) / max(falloff.y - falloff.x, 1e-4));
+    return mix(1.0, falloff.z, t);
+}
+
+#ifdef ARENA
+// Chunks are sorted by `first_instance`.
+fn find_chunk(instance: u32) -> u32 {
+    var low = 0u;
+    var high = arena.chunk_count;
+    while (high - low > 1u) {
+        let mid = (low + high) / 2u;
+        if (chunks[mid].first_instance <= instance) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    return low;
+}
+#endif
+
+fn instance_world_matrix(instance: InstanceData) -> mat4x4<f32> {
+#ifdef ORIENTED_INSTANCES
+    return calculate_oriented_instance_world_matrix(
+        instance.position,
+        instance.rotation,
+        instance.scale,
+        lod_data.world_from_local
+    );
+#else
+    return calculate_instance_world_matrix(
+        instance.pos_and_scale,
+        instance.rotation,
+        lod_data.world_from_local
+    );
+#endif
+}
+
+fn instance_position(instance: InstanceData) -> vec3<f32> {
+#ifdef ORIENTED_INSTANCES
+    return instance.position;
+#else
+    return instance.pos_and_scale.xyz;
+#endif
+}
+
+fn scale_instance(instance: InstanceData, factor: f32) -> InstanceData {
+    var scaled = instance;
+#ifdef ORIENTED_INSTANCES
+    scaled.scale *= factor;
+#else
+    scaled.pos_and_scale.w *= factor;
+#endif
+    return scaled;
+}
+
+fn max_axis_scale(m: mat4x4<f32>) -> f32 {
+    return sqrt(max(max(dot(m[0].xyz, m[0].xyz), dot(m[1].xyz, m[1].xyz)), dot(m[2].xyz, m[2].xyz)));
+}
+
+// Same test as `Frustum::intersects_sphere` in bevy_camera.
+fn is_sphere_in_frustum(center: vec3<f32>, radius: f32) -> bool {
+    let center_w = vec4<f32>(center, 1.0);
+    for (var i = 0u; i < 6u; i++) {
+        if (dot(camera.frustum[i], center_w) + radius <= 0.0) {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Approximate diameter of the sphere on screen in pixels.
+fn projected_diameter(center: vec3<f32>, radius: f32) -> f32 {
+    // `y` scale of the projection, the last column is `(0, 0, 0, 1)` for orthographic ones.
+    let scale = camera.clip_from_view[1][1];
+    let orthographic = camera.clip_from_view[3][3] == 1.0;
+
+    let dist = max(distance(center, camera.view_pos.xyz), 1e-4);
+    let ndc_radius = select(radius * scale / dist, radius * scale, orthographic);
+
+    // NDC spans two units over the viewport height.
+    return ndc_radius * camera.viewport.w;
+}
+
+// Tests the sphere against the depth pyramid of the previous frame.
+fn is_sphere_occluded(center: vec3<f32>, radius: f32) -> bool {
+    let mip_count = camera.depth_pyramid_mip_count;
+    if (mip_count == 0u) { return false; }
+
+    var ndc_min = vec2<f32>(1.0);
+    var ndc_max = vec2<f32>(-1.0);
+    var nearest_depth = 0.0;
+
+    for (var i = 0u; i < 8u; i++) {
+        let corner = center + radius * vec3<f32>(
+            select(-1.0, 1.0, (i & 1u) != 0u),
+            select(-1.0, 1.0, (i & 2u) != 0u),
+            select(-1.0, 1.0, (i & 4u) != 0u),
+        );
+        let clip = camera.occlusion_clip_from_world * vec4<f32>(corner, 1.0);
+
+        // Crosses the near plane of the previous frame.
+        if (clip.w <= 0.0) { return false; }
+
+        let ndc = clip.xyz / clip.w;
+        ndc_min = min(ndc_min, ndc.xy);
+        ndc_max = max(ndc_max, ndc.xy);
+        nearest_depth = max(nearest_depth, ndc.z);
+    }
+
+    let uv_min = saturate(vec2<f32>(ndc_min.x, -ndc_max.y) * 0.5 + 0.5);
+    let uv_max = saturate(vec2<f32>(ndc_max.x, -ndc_min.y) * 0.5 + 0.5);
+
+    // Mip 0 is half the resolution of the depth buffer.
+    let viewport = camera.occlusion_viewport;
+    let pixel_min = (viewport.xy + uv_min * viewport.zw) * 0.5;
+    let pixel_max = (viewport.xy + uv_max * viewport.zw) * 0.5;
+
+    // Pick the mip where the bounds cover at most 2x2 texels.
+    let extent = max(pixel_max.x - pixel_min.x, pixel_max.y - pixel_min.y);
+    let level = min(u32(ceil(log2(max(extent, 1.0)))), mip_count - 1u);
+
+    let texel_scale = 1.0 / f32(1u << level);
+    let max_texel = vec2<i32>(textureDimensions(depth_pyramid, level)) - 1;
+    let texel_min = clamp(vec2<i32>(pixel_min * texel_scale), vec2<i32>(0), max_texel);
+    let texel_max = clamp(vec2<i32>(pixel_max * texel_scale), vec2<i32>(0), max_texel);
+
+    let occluder_depth = min(
+        min(
+            textureLoad(depth_pyramid, texel_min, level).r,
+            textureLoad(depth_pyramid, vec2<i32>(texel_max.x, texel_min.y), level).r
+        ),
+        min(
+            textureLoad(depth_pyramid, vec2<i32>(texel_min.x, texel_max.y), level).r,
+            textureLoad(depth_pyramid, texel_max, level).r
+        )
+    );
+
+    // Reverse-Z, the instance is occluded if its nearest point is behind the farthest occluder.
+    return nearest_depth < occluder_depth;
+}
+
+// Instances and draws of the current entity or arena chunk in the current view.
+struct CullRange {
+    first_instance: u32,
+    instance_count: u32,
+    first_draw: u32,
+    first_output: u32,
+}
+
+#ifdef ARENA
+fn load_chunk(chunk_index: u32) -> CullRange {
+    let chunk = chunks[chunk_index];
+    lod_data = chunk.lod;
+
+    let non_indexed = (lod_data.flags & INSTANCE_CULL_FLAGS_NON_INDEXED) != 0u;
+    let view_draw_stride = select(arena.indexed_view_draw_stride, arena.non_indexed_view_draw_stride, non_indexed);
+
+    return CullRange(
+        chunk.first_instance,
+        chunk.instance_count,
+        camera.view_index * view_draw_stride + chunk.first_draw,
+        camera.view_index * arena.view_output_stride + chunk.first_output,
+    );
+}
+#else
+fn load_chunk() -> CullRange {
+    let instance_count = arrayLength(&source_buffer);
+    let first_draw = camera.view_index * lod_data.lod_count;
+    return CullRange(0u, instance_count, first_draw, first_draw * instance_count);
+}
+#endif
+
+// Adds to the instance count of a draw and returns the previous count.
+fn add_instances(draw_index: u32, count: u32) -> u32 {
+#ifdef ARENA
+    if ((lod_data.flags & INSTANCE_CULL_FLAGS_NON_INDEXED) != 0u) {
+        return atomicAdd(&non_indexed_indirect_args[draw_index].instance_count, count);
+    }
+#endif
+    return atomicAdd(&indirect_args[draw_index].instance_count, count);
+}
+
+// Marks an instance as culled before any test can reject it, see `write_instance`.
+fn reset_visibility(i: u32) {
+    if ((lod_data.flags & INSTANCE_CULL_FLAGS_STABLE_COMPACTION) != 0u) {
+        visibility[i].lod = INSTANCE_CULLED;
+    }
+}
+
+fn select_lod(dist: f32) -> u32 {
+    var lod = 0u;
+    for (var level = 1u; level < lod_data.lod_count; level++) {
+        if (dist >= lod_data.lod_distances[level - 1u]) {
+            lod = level;
+        }
+    }
+    return lod;
+}
+
+// Writes a visible instance into the draw of its LOD level.
+//
+// Stable compaction only marks the instance, `compact_instances` writes it in source order.
+fn write_instance(i: u32, range: CullRange, lod: u32, instance: InstanceData, scale_factor: f32) {
+    if ((lod_data.flags & INSTANCE_CULL_FLAGS_STABLE_COMPACTION) != 0u) {
+        visibility[i] = InstanceVisibility(lod, scale_factor);
+        return;
+    }
+
+    // Every view and LOD level has its own draw and range of `instance_count` instances.
+    let write_index = add_instances(range.first_draw + lod, 1u);
+
+    instance_buffer[range.first_output + lod * range.instance_count + write_index] = instance;
+}
+
+const COMPACT_WORKGROUP_SIZE: u32 = 256u;
+
+var<workgroup> scan: array<u32, COMPACT_WORKGROUP_SIZE>;
+
+// Writes the instances marked by `write_instance` in source order, called by every invocation
+// of a `COMPACT_WORKGROUP_SIZE` workgroup per entity or arena chunk.
+fn compact_instances(local_index: u32, range: CullRange) {
+    if ((lod_data.flags & INSTANCE_CULL_FLAGS_STABLE_COMPACTION) == 0u) { return; }
+
+    for (var lod = 0u; lod < lod_data.lod_count; lod++) {
+        var total = 0u;
+
+        for (var block = 0u; block < range.instance_count; block += COMPACT_WORKGROUP_SIZE) {
+            let i = block + local_index;
+
+            var entry = InstanceVisibility(INSTANCE_CULLED, 0.0);
+            if (i < range.instance_count) {
+                entry = visibility[range.first_instance + i];
+            }
+            let visible = entry.lod == lod;
+
+            scan[local_index] = select(0u, 1u, visible);
+            workgroupBarrier();
+
+            // Inclusive Hillis-Steele scan of the visible flags.
+            for (var offset = 1u; offset < COMPACT_WORKGROUP_SIZE; offset <<= 1u) {
+                var value = scan[local_index];
+                if (local_index >= offset) {
+                    value += scan[local_index - offset];
+                }
+                workgroupBarrier();
+                scan[local_index] = value;
+                workgroupBarrier();
+            }
+
+            if (visible) {
+                let instance = scale_instance(source_buffer[range.first_instance + i], entry.scale_factor);
+
+                let write_index = total + scan[local_index] - 1u;
+                instance_buffer[range.first_output + lod * range.instance_count + write_index] = instance;
+            }
+
+            total += workgroupUniformLoad(&scan[COMPACT_WORKGROUP_SIZE - 1u]);
+            workgroupBarrier();
+        }
+
+        if (local_index == 0u) {
+            _ = add_instances(range.first_draw + lod, total);
+        }
+
+        if ((lod_data.flags & INSTANCE_CULL_FLAGS_SORT) != 0u) {
+            storageBarrier();
+            sort_instances(local_index, range.first_output + lod * range.instance_count, total);
+        }
+    }
+}
+
+fn view_distance_squared(instance: InstanceData) -> f32 {
+    let world_pos = (lod_data.world_from_local * vec4<f32>(instance_position(instance), 1.0)).xyz;
+    let offset = world_pos - camera.view_pos.xyz;
+    return dot(offset, offset);
+}
+
+// Moves the farther instance of `a < b` to `a`, indices past `count` are treated as the nearest.
+fn sort_pair(first: u32, count: u32, a: u32, b: u32) {
+    if (b >= count) { return; }
+
+    let instance_a = instance_buffer[first + a];
+    let instance_b = instance_buffer[first + b];
+    if (view_distance_squared(instance_a) < view_distance_squared(instance_b)) {
+        instance_buffer[first + a] = instance_b;
+        instance_buffer[first + b] = instance_a;
+    }
+}
+
+// Sorts `count` instances starting at `first` back to front, called by every invocation of a
+// `COMPACT_WORKGROUP_SIZE` workgroup.
+//
+// Bitonic sort over the next power of two, every compare-exchange moves the farther instance to
+// the lower index so the padding never has to be written.
+fn sort_instances(local_index: u32, first: u32, count: u32) {
+    var size = 2u;
+    while (size < count) { size <<= 1u; }
+
+    for (var block = 2u; block <= size; block <<= 1u) {
+        // Flip: compare mirrored pairs of each block.
+        for (var pair = local_index; pair < size / 2u; pair += COMPACT_WORKGROUP_SIZE) {
+            let half = block / 2u;
+            let start = (pair / half) * block;
+            let offset = pair % half;
+            sort_pair(first, count, start + offset, start + block - 1u - offset);
+        }
+        storageBarrier();
+
+        // Disperse: compare pairs at a halving distance.
+        for (var step = block / 2u; step >= 2u; step >>= 1u) {
+            for (var pair = local_index; pair < size / 2u; pair += COMPACT_WORKGROUP_SIZE) {
+                let half = step / 2u;
+                let a = (pair / half) * step + pair % half;
+                sort_pair(first, count, a, a + half);
+            }
+            storageBarrier();
+        }
+    }
+}
@@ -3,7 +3,8 @@ pub mod pipeline;
 pub mod plugin;
 pub mod prepare;
 pub mod queue;
+pub mod readback;
 
 pub mod prelude {
-    pub use super::plugin::*;
+    pub use super::{plugin::*, readback::InstanceCullDiagnostics};
 }
@@ -1,17 +1,25 @@
-use bevy_ecs::prelude::*;
+use bevy_core_pipeline::prepass::ViewPrepassTextures;
+use bevy_ecs::{prelude::*, query::QueryItem};
 use bevy_render::{
-    render_graph::{Node, NodeRunError, RenderGraphContext},
-    render_resource::{CachedPipelineState, ComputePassDescriptor, PipelineCache},
+    camera::ExtractedCamera,
+    render_graph::{Node, NodeRunError, RenderGraphContext, ViewNode},
+    render_resource::{
+        BindGroupEntries, CachedPipelineState, ComputePassDescriptor, PipelineCache,
+    },
     renderer::RenderContext,
+    view::ExtractedView,
 };
 
 #[cfg(feature = "trace")]
 use tracing::{error, trace, warn};
 
 use crate::components::{
-    GpuDrawIndexedIndirect, InstancedComputeBindGroup, InstancedComputeSourceBuffer,
+    GpuDrawIndirect, InstancedComputeBindGroup, InstancedComputeSourceBuffer,
+    InstancedDepthPyramid, ViewCullBuffer,
 };
-use crate::{cull::pipeline::InstancedComputePipeline, resources::GlobalCullBuffer};
+use crate::cull::pipeline::{DepthPyramidPipeline, InstancedComputePipeline};
+use crate::cull::readback::PendingInstanceCountReadback;
+use crate::resources::{InstanceCullArena, InstancedCullPipelines};
 
 enum InstancedComputeNodeState {
     Loading,
@@ -21,10 +29,12 @@ enum InstancedComputeNodeState {
 pub struct InstancedComputeNode {
     state: InstancedComputeNodeState,
     query: QueryState<(
+        Entity,
         &'static InstancedComputeSourceBuffer,
         &'static InstancedComputeBindGroup,
-        &'static GpuDrawIndexedIndirect,
+        Has<GpuDrawIndirect>,
     )>,
+    views: QueryState<&'static ViewCullBuffer, (With<ExtractedView>, With<ExtractedCamera>)>,
 }
 
 impl FromWorld for InstancedComputeNode {
@@ -32,6 +42,7 @@ impl FromWorld for InstancedComputeNode {
         Self {
             state: InstancedComputeNodeState::Loading,
             query: world.query_filtered(),
+            views: world.query_filtered(),
         }
     }
 }
@@ -49,6 +60,7 @@ impl Node for InstancedComputeNode {
         }
 
         self.query.update_archetypes(world);
+        self.views.update_archetypes(world);
     }
 
     fn run(
@@ -89,7 +101,12 @@ impl Node for InstancedComputeNode {
         else {
             return Ok(());
         };
-
+        let get_pipeline =
+            |id: Option<_>| id.and_then(|id| pipeline_cache.get_compute_pipeline(id));
+        let non_indexed_pipeline = get_pipeline(pipeline_res.non_indexed_pipeline_id);
+        let compact_pipeline = get_pipeline(pipeline_res.compact_pipeline_id);
+        let non_indexed_compact_pipeline =
+            get_pipeline(pipeline_res.non_indexed_compact_pipeline_id);
         let mut pass =
             render_context
                 .command_encoder()
@@ -98,21 +115,204 @@ impl Node for InstancedComputeNode {
                     timestamp_writes: None,
                 });
 
-        pass.set_pipeline(pipeline);
-
-        if let Some(global_buffer) = world.get_resource::<GlobalCullBuffer>() {
-            pass.set_bind_group(1, &global_buffer.bind_group, &[]);
-        } else {
+        if self.views.iter_manual(world).next().is_none() {
             #[cfg(feature = "trace")]
-            warn!("No global cull buffer found. Skipping instanced material gpu compute culling.");
+            warn!("No view cull buffer found. Skipping instanced material gpu compute culling.");
             return Ok(());
         }
 
-        for (source, bind_group, _indirect) in self.query.iter_manual(world) {
-            pass.set_bind_group(0, &bind_group.0, &[]);
+        let arena = world
+            .resource::<InstanceCullArena>()
+            .buffers
+            .as_ref()
+            .zip(get_pipeline(pipeline_res.arena_pipeline_id));
+        let arena_compact_pipeline = get_pipeline(pipeline_res.arena_compact_pipeline_id);
+        let cull_pipelines = world.resource::<InstancedCullPipelines>();
+
+        for view_cull_buffer in self.views.iter_manual(world) {
+            pass.set_bind_group(1, &view_cull_buffer.bind_group, &[]);
+
+            // All chunks of the arena are culled at once.
+            if let Some((buffers, arena_pipeline)) = arena
+                && view_cull_buffer.view_index < buffers.view_count
+            {
+                pass.set_pipeline(arena_pipeline);
+                pass.set_bind_group(0, &buffers.bind_group, &[]);
+                pass.dispatch_workgroups(buffers.instance_count.div_ceil(64), 1, 1);
+
+                // One workgroup per chunk, chunks without stable compaction return early.
+                if buffers.visibility.is_some()
+                    && let Some(arena_compact_pipeline) = arena_compact_pipeline
+                {
+                    pass.set_pipeline(arena_compact_pipeline);
+                    pass.dispatch_workgroups(buffers.chunk_count, 1, 1);
+                }
+            }
+
+            for (entity, source, bind_group, non_indexed) in self.query.iter_manual(world) {
+                // Buffers are reallocated once the view count changes.
+                if view_cull_buffer.view_index >= source.view_count {
+                    continue;
+                }
+
+                let (pipeline, compact_pipeline) =
+                    if let Some(material) = cull_pipelines.get(&entity) {
+                        let (pipeline_id, compact_pipeline_id) = if non_indexed {
+                            (
+                                material.non_indexed_pipeline_id,
+                                material.non_indexed_compact_pipeline_id,
+                            )
+                        } else {
+                            (material.pipeline_id, material.compact_pipeline_id)
+                        };
+                        let Some(pipeline) = get_pipeline(Some(pipeline_id)) else {
+                            continue;
+                        };
+
+                        if let Some(material_bind_group) = &material.material_bind_group {
+                            pass.set_bind_group(2, material_bind_group, &[]);
+                        }
+                        (pipeline, get_pipeline(Some(compact_pipeline_id)))
+                    } else if non_indexed {
+                        let Some(non_indexed_pipeline) = non_indexed_pipeline else {
+                            continue;
+                        };
+                        (non_indexed_pipeline, non_indexed_compact_pipeline)
+                    } else {
+                        (pipeline, compact_pipeline)
+                    };
+
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group.0, &[]);
+
+                let workgroups = (source.count as f32 / 64.0).ceil() as u32;
+                pass.dispatch_workgroups(workgroups, 1, 1);
+
+                if source.stable_compaction
+                    && let Some(compact_pipeline) = compact_pipeline
+                {
+                    pass.set_pipeline(compact_pipeline);
+                    pass.dispatch_workgroups(1, 1, 1);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies the indirect draw arguments written by [`InstancedComputeNode`] into the staging
+/// buffer of the instance count readback.
+#[derive(Default)]
+pub struct InstanceCountReadbackNode;
+
+impl Node for InstanceCountReadbackNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let Some(readback) = world
+            .get_resource::<PendingInstanceCountReadback>()
+            .and_then(|pending| pending.readback.as_ref())
+        else {
+            return Ok(());
+        };
+
+        let command_encoder = render_context.command_encoder();
+        for (buffer, offset) in &readback.copies {
+            command_encoder.copy_buffer_to_buffer(
+                buffer,
+                0,
+                &readback.staging,
+                *offset,
+                buffer.size(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Downsamples the depth prepass of a view into its [`InstancedDepthPyramid`].
+///
+/// The pyramid is used by the cull pass of the next frame.
+#[derive(Default)]
+pub struct DepthPyramidNode;
+
+impl ViewNode for DepthPyramidNode {
+    type ViewQuery = (&'static ViewPrepassTextures, &'static InstancedDepthPyramid);
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (prepass_textures, depth_pyramid): QueryItem<'w, '_, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let Some(depth) = prepass_textures.depth.as_ref() else {
+            return Ok(());
+        };
+
+        let pipeline_res = world.resource::<DepthPyramidPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let (first_layout, first_pipeline_id) = if depth.texture.texture.sample_count() > 1 {
+            (
+                &pipeline_res.first_multisampled_layout,
+                pipeline_res.first_multisampled_pipeline_id,
+            )
+        } else {
+            (&pipeline_res.first_layout, pipeline_res.first_pipeline_id)
+        };
+
+        let (Some(first_pipeline), Some(downsample_pipeline)) = (
+            first_pipeline_id.and_then(|id| pipeline_cache.get_compute_pipeline(id)),
+            pipeline_res
+                .downsample_pipeline_id
+                .and_then(|id| pipeline_cache.get_compute_pipeline(id)),
+        ) else {
+            return Ok(());
+        };
+
+        let render_device = render_context.render_device().clone();
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("instanced_depth_pyramid_pass"),
+                    timestamp_writes: None,
+                });
+
+        for (mip, output) in depth_pyramid.mips.iter().enumerate() {
+            let (pipeline, bind_group) = if mip == 0 {
+                (
+                    first_pipeline,
+                    render_device.create_bind_group(
+                        "instanced_depth_pyramid_first_bind_group",
+                        first_layout,
+                        &BindGroupEntries::sequential((&depth.texture.default_view, output)),
+                    ),
+                )
+            } else {
+                (
+                    downsample_pipeline,
+                    render_device.create_bind_group(
+                        "instanced_depth_pyramid_downsample_bind_group",
+                        &pipeline_res.downsample_layout,
+                        &BindGroupEntries::sequential((&depth_pyramid.mips[mip - 1], output)),
+                    ),
+                )
+            };
+
+            let width = (depth_pyramid.texture.width() >> mip).max(1);
+            let height = (depth_pyramid.texture.height() >> mip).max(1);
 
-            let workgroups = (source.count as f32 / 64.0).ceil() as u32;
-            pass.dispatch_workgroups(workgroups, 1, 1);
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(width.div_ceil(8), height.div_ceil(8), 1);
         }
 
         Ok(())
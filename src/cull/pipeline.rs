@@ -1,18 +1,30 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
 use std::num::NonZeroU64;
 
 use bevy_asset::{AssetPath, AssetServer, Handle, embedded_path};
 use bevy_ecs::prelude::*;
 use bevy_render::{
     render_resource::{
-        BindGroupLayout, BindGroupLayoutEntry, BindingType, BufferBindingType,
-        CachedComputePipelineId, ShaderStages, ShaderType,
+        BindGroupLayout, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType,
+        BufferDescriptor, BufferUsages, CachedComputePipelineId, ComputePipelineDescriptor,
+        DrawIndexedIndirectArgs, DrawIndirectArgs, Extent3d, ShaderStages, ShaderType,
+        SpecializedComputePipeline, StorageTextureAccess, TextureDescriptor, TextureDimension,
+        TextureFormat, TextureSampleType, TextureUsages, TextureView, TextureViewDescriptor,
+        TextureViewDimension,
     },
     renderer::RenderDevice,
 };
-use bevy_shader::Shader;
+use bevy_shader::{Shader, ShaderRef};
+use bevy_utils::default;
 
-use crate::components::InstanceData;
-use crate::resources::{CameraCullData, LodCullData};
+use bitflags::bitflags;
+
+use crate::components::{InstanceData, InstanceLayout};
+use crate::material::InstancedMaterial;
+use crate::render::pipeline::InstancedMaterialPipeline;
+use crate::resources::{ArenaChunkData, ArenaCullData, CameraCullData, LodCullData};
 
 #[derive(Resource)]
 pub struct InstancedComputePipeline {
@@ -20,6 +32,20 @@ pub struct InstancedComputePipeline {
     pub global_layout: BindGroupLayout,
     pub shader: Handle<Shader>,
     pub pipeline_id: Option<CachedComputePipelineId>,
+    /// Variant of `pipeline_id` that writes `DrawIndirectArgs` for non-indexed meshes.
+    pub non_indexed_pipeline_id: Option<CachedComputePipelineId>,
+    /// Replaces `entity_layout` for the [`InstanceCullArena`](crate::resources::InstanceCullArena).
+    pub arena_layout: BindGroupLayout,
+    /// Culls all chunks of the arena in a single dispatch.
+    pub arena_pipeline_id: Option<CachedComputePipelineId>,
+    /// `compact` entry points of `pipeline_id`, `non_indexed_pipeline_id` and `arena_pipeline_id`.
+    pub compact_pipeline_id: Option<CachedComputePipelineId>,
+    pub non_indexed_compact_pipeline_id: Option<CachedComputePipelineId>,
+    pub arena_compact_pipeline_id: Option<CachedComputePipelineId>,
+    /// Bound instead of the depth pyramid if there is none, never occludes anything.
+    pub dummy_depth_pyramid: TextureView,
+    /// Bound instead of the visibility buffer of entities without stable compaction.
+    pub dummy_visibility: Buffer,
 }
 
 impl FromWorld for InstancedComputePipeline {
@@ -31,6 +57,66 @@ impl FromWorld for InstancedComputePipeline {
 
         let entity_layout = render_device.create_bind_group_layout(
             "instanced_material_compute_entity_layout",
+            &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        // Depends on the `InstanceLayout` of the entity, validated against the shader
+                        // when binding.
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        // `DrawIndirectArgs` or `DrawIndexedIndirectArgs` depending on the mesh,
+                        // validated against the shader when binding.
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(LodCullData::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(INSTANCE_VISIBILITY_SIZE),
+                    },
+                    count: None,
+                },
+            ],
+        );
+
+        let arena_layout = render_device.create_bind_group_layout(
+            "instanced_material_compute_arena_layout",
             &[
                 BindGroupLayoutEntry {
                     binding: 0,
@@ -58,17 +144,49 @@ impl FromWorld for InstancedComputePipeline {
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: false },
                         has_dynamic_offset: false,
-                        min_binding_size: NonZeroU64::new(20),
+                        min_binding_size: NonZeroU64::new(
+                            size_of::<DrawIndexedIndirectArgs>() as u64
+                        ),
                     },
                     count: None,
                 },
                 BindGroupLayoutEntry {
                     binding: 3,
                     visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(ArenaChunkData::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false,
-                        min_binding_size: Some(LodCullData::min_size()),
+                        min_binding_size: Some(ArenaCullData::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(size_of::<DrawIndirectArgs>() as u64),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(INSTANCE_VISIBILITY_SIZE),
                     },
                     count: None,
                 },
@@ -77,18 +195,50 @@ impl FromWorld for InstancedComputePipeline {
 
         let global_layout = render_device.create_bind_group_layout(
             "instanced_material_compute_global_layout",
-            &[BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::COMPUTE,
-                ty: BindingType::Buffer {
-                    ty: BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: Some(CameraCullData::min_size()),
+            &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(CameraCullData::min_size()),
+                    },
+                    count: None,
                 },
-                count: None,
-            }],
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
         );
 
+        let dummy_depth_pyramid = render_device
+            .create_texture(&TextureDescriptor {
+                label: Some("instanced_material_dummy_depth_pyramid"),
+                size: Extent3d::default(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: DEPTH_PYRAMID_FORMAT,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&TextureViewDescriptor::default());
+
+        let dummy_visibility = render_device.create_buffer(&BufferDescriptor {
+            label: Some("instanced_material_dummy_visibility_buffer"),
+            size: INSTANCE_VISIBILITY_SIZE,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
         let shader = asset_server
             .load(AssetPath::from_path_buf(embedded_path!("compute.wgsl")).with_source("embedded"));
 
@@ -97,6 +247,288 @@ impl FromWorld for InstancedComputePipeline {
             global_layout,
             shader,
             pipeline_id: None,
+            non_indexed_pipeline_id: None,
+            arena_layout,
+            arena_pipeline_id: None,
+            compact_pipeline_id: None,
+            non_indexed_compact_pipeline_id: None,
+            arena_compact_pipeline_id: None,
+            dummy_depth_pyramid,
+            dummy_visibility,
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct InstancedCullPipelineFlags: u32 {
+        /// Writes `DrawIndirectArgs` for non-indexed meshes.
+        const NON_INDEXED = 1 << 0;
+        /// Uses the `compact` entry point instead of `main`.
+        const COMPACT = 1 << 1;
+    }
+}
+
+/// Key of the default cull pipeline for entities with a non-default [`InstanceLayout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstancedCullPipelineKey {
+    pub flags: InstancedCullPipelineFlags,
+    pub instance_layout: InstanceLayout,
+}
+
+impl SpecializedComputePipeline for InstancedComputePipeline {
+    type Key = InstancedCullPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let mut shader_defs = key.instance_layout.shader_defs();
+        if key.flags.contains(InstancedCullPipelineFlags::NON_INDEXED) {
+            shader_defs.push("NON_INDEXED".into());
+        }
+
+        let entry_point = if key.flags.contains(InstancedCullPipelineFlags::COMPACT) {
+            "compact"
+        } else {
+            "main"
+        };
+
+        ComputePipelineDescriptor {
+            label: Some("instanced_material_compute_layout_pipeline".into()),
+            layout: vec![self.entity_layout.clone(), self.global_layout.clone()],
+            push_constant_ranges: vec![],
+            shader: self.shader.clone(),
+            shader_defs,
+            entry_point: Some(entry_point.into()),
+            ..default()
+        }
+    }
+}
+
+pub struct InstancedMaterialCullPipelineKey<M: InstancedMaterial> {
+    pub flags: InstancedCullPipelineFlags,
+    pub instance_layout: InstanceLayout,
+    pub bind_group_data: M::Data,
+}
+
+impl<M> Clone for InstancedMaterialCullPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            flags: self.flags,
+            instance_layout: self.instance_layout,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
+
+impl<M> PartialEq for InstancedMaterialCullPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.flags == other.flags
+            && self.instance_layout == other.instance_layout
+            && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M> Eq for InstancedMaterialCullPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: Eq,
+{
+}
+
+impl<M> Hash for InstancedMaterialCullPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: Hash,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.flags.hash(state);
+        self.instance_layout.hash(state);
+        self.bind_group_data.hash(state);
+    }
+}
+
+impl<M> fmt::Debug for InstancedMaterialCullPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstancedMaterialCullPipelineKey")
+            .field("flags", &self.flags)
+            .field("instance_layout", &self.instance_layout)
+            .field("bind_group_data", &self.bind_group_data)
+            .finish()
+    }
+}
+
+/// Cull pipeline of a material with a custom [`InstancedMaterial::cull_shader`].
+#[derive(Resource)]
+pub struct InstancedMaterialCullPipeline<M: InstancedMaterial> {
+    /// Group 0, see [`InstancedComputePipeline::entity_layout`].
+    pub entity_layout: BindGroupLayout,
+    /// Group 1, see [`InstancedComputePipeline::global_layout`].
+    pub global_layout: BindGroupLayout,
+    /// Group 2, see [`InstancedMaterialPipeline::material_layout`].
+    pub material_layout: BindGroupLayout,
+    pub shader: Handle<Shader>,
+    pub _phantom: PhantomData<M>,
+}
+
+impl<M: InstancedMaterial> FromWorld for InstancedMaterialCullPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        let material_layout = world
+            .resource::<InstancedMaterialPipeline<M>>()
+            .material_layout
+            .clone();
+
+        let compute_pipeline = world.get_resource_or_init::<InstancedComputePipeline>();
+        let entity_layout = compute_pipeline.entity_layout.clone();
+        let global_layout = compute_pipeline.global_layout.clone();
+        let default_shader = compute_pipeline.shader.clone();
+
+        let shader = match M::cull_shader() {
+            ShaderRef::Default => default_shader,
+            ShaderRef::Handle(handle) => handle,
+            ShaderRef::Path(path) => world.resource::<AssetServer>().load(path),
+        };
+
+        InstancedMaterialCullPipeline {
+            entity_layout,
+            global_layout,
+            material_layout,
+            shader,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<M> SpecializedComputePipeline for InstancedMaterialCullPipeline<M>
+where
+    M: InstancedMaterial,
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = InstancedMaterialCullPipelineKey<M>;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let mut shader_defs = key.instance_layout.shader_defs();
+        if key.flags.contains(InstancedCullPipelineFlags::NON_INDEXED) {
+            shader_defs.push("NON_INDEXED".into());
+        }
+
+        let entry_point = if key.flags.contains(InstancedCullPipelineFlags::COMPACT) {
+            "compact"
+        } else {
+            "main"
+        };
+
+        let mut descriptor = ComputePipelineDescriptor {
+            label: Some(
+                format!(
+                    "instanced_material_cull_pipeline_{}",
+                    std::any::type_name::<M>()
+                )
+                .into(),
+            ),
+            layout: vec![
+                self.entity_layout.clone(),
+                self.global_layout.clone(),
+                self.material_layout.clone(),
+            ],
+            push_constant_ranges: vec![],
+            shader: self.shader.clone(),
+            shader_defs,
+            entry_point: Some(entry_point.into()),
+            ..default()
+        };
+
+        M::specialize_cull(&mut descriptor, key.bind_group_data);
+
+        descriptor
+    }
+}
+
+/// Size of an `InstanceVisibility` in `types.wgsl`.
+pub const INSTANCE_VISIBILITY_SIZE: u64 = 8;
+
+pub const DEPTH_PYRAMID_FORMAT: TextureFormat = TextureFormat::R32Float;
+
+/// Pipelines that downsample the depth prepass into an [`InstancedDepthPyramid`](crate::components::InstancedDepthPyramid).
+#[derive(Resource)]
+pub struct DepthPyramidPipeline {
+    /// Reads the depth prepass and writes mip 0.
+    pub first_layout: BindGroupLayout,
+    /// Same as `first_layout` for a multisampled depth prepass.
+    pub first_multisampled_layout: BindGroupLayout,
+    /// Reads mip `n - 1` and writes mip `n`.
+    pub downsample_layout: BindGroupLayout,
+    pub shader: Handle<Shader>,
+    pub first_pipeline_id: Option<CachedComputePipelineId>,
+    pub first_multisampled_pipeline_id: Option<CachedComputePipelineId>,
+    pub downsample_pipeline_id: Option<CachedComputePipelineId>,
+}
+
+impl FromWorld for DepthPyramidPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let asset_server = world.resource::<AssetServer>();
+
+        let output = BindGroupLayoutEntry {
+            binding: 1,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::StorageTexture {
+                access: StorageTextureAccess::WriteOnly,
+                format: DEPTH_PYRAMID_FORMAT,
+                view_dimension: TextureViewDimension::D2,
+            },
+            count: None,
+        };
+
+        let input = |sample_type, multisampled| BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type,
+                view_dimension: TextureViewDimension::D2,
+                multisampled,
+            },
+            count: None,
+        };
+
+        let first_layout = render_device.create_bind_group_layout(
+            "instanced_material_depth_pyramid_first_layout",
+            &[input(TextureSampleType::Depth, false), output],
+        );
+        let first_multisampled_layout = render_device.create_bind_group_layout(
+            "instanced_material_depth_pyramid_first_multisampled_layout",
+            &[input(TextureSampleType::Depth, true), output],
+        );
+        let downsample_layout = render_device.create_bind_group_layout(
+            "instanced_material_depth_pyramid_downsample_layout",
+            &[
+                input(TextureSampleType::Float { filterable: false }, false),
+                output,
+            ],
+        );
+
+        let shader = asset_server.load(
+            AssetPath::from_path_buf(embedded_path!("depth_pyramid.wgsl")).with_source("embedded"),
+        );
+
+        DepthPyramidPipeline {
+            first_layout,
+            first_multisampled_layout,
+            downsample_layout,
+            shader,
+            first_pipeline_id: None,
+            first_multisampled_pipeline_id: None,
+            downsample_pipeline_id: None,
         }
     }
 }
@@ -1,54 +1,154 @@
 use crate::cull::{
-    node::InstancedComputeNode,
-    pipeline::InstancedComputePipeline,
-    prepare::{prepare_global_cull_buffer, prepare_instanced_material_compute_resources},
-    queue::queue_instanced_material_compute_pipeline,
+    node::{DepthPyramidNode, InstanceCountReadbackNode, InstancedComputeNode},
+    pipeline::{DepthPyramidPipeline, InstancedComputePipeline},
+    prepare::{
+        prepare_depth_pyramid, prepare_instance_cull_arena,
+        prepare_instanced_material_compute_resources, prepare_view_cull_buffers,
+    },
+    queue::{
+        clear_instanced_cull_pipelines, queue_depth_pyramid_pipelines,
+        queue_instanced_material_compute_pipeline, specialize_instance_layout_cull_pipelines,
+    },
+    readback::{
+        InstanceCountReadback, InstanceCullDiagnostics, PendingInstanceCountReadback,
+        map_instance_count_readback, prepare_instance_count_readback,
+        update_visible_instance_counts,
+    },
 };
 use crate::prelude::*;
 
 use bevy_app::prelude::*;
 use bevy_asset::embedded_asset;
+use bevy_core_pipeline::core_3d::graph::{Core3d, Node3d};
+use bevy_diagnostic::{Diagnostic, RegisterDiagnostic};
 use bevy_ecs::prelude::*;
 use bevy_render::{
-    Render, RenderApp, RenderSystems, extract_component::ExtractComponentPlugin,
-    graph::CameraDriverLabel, render_graph::RenderGraph,
+    Render, RenderApp, RenderSystems,
+    extract_component::ExtractComponentPlugin,
+    extract_resource::ExtractResourcePlugin,
+    graph::CameraDriverLabel,
+    render_graph::{RenderGraph, RenderGraphExt, RenderLabel, ViewNodeRunner},
+    render_resource::SpecializedComputePipelines,
+    renderer::render_system,
 };
 use bevy_shader::load_shader_library;
 
-pub struct GpuComputeCullPlugin;
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct InstancedDepthPyramidLabel;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct InstanceCountReadbackLabel;
+
+#[derive(Default)]
+pub struct GpuComputeCullPlugin {
+    /// Use stable compaction for all entities, see [`GpuStableCompaction`].
+    pub stable_compaction: bool,
+}
 
 impl Plugin for GpuComputeCullPlugin {
     fn build(&self, app: &mut App) {
         load_shader_library!(app, "types.wgsl");
         load_shader_library!(app, "bindings.wgsl");
+        load_shader_library!(app, "functions.wgsl");
 
         embedded_asset!(app, "compute.wgsl");
+        embedded_asset!(app, "depth_pyramid.wgsl");
+
+        app.add_plugins((
+            ExtractComponentPlugin::<GpuCullCompute>::default(),
+            ExtractComponentPlugin::<GpuOcclusionCull>::default(),
+            ExtractComponentPlugin::<GpuStableCompaction>::default(),
+            ExtractComponentPlugin::<GpuInstanceSort>::default(),
+            ExtractComponentPlugin::<InstanceBoundingSphere>::default(),
+            ExtractComponentPlugin::<InstanceLods>::default(),
+            ExtractComponentPlugin::<InstanceDensityFalloff>::default(),
+            ExtractComponentPlugin::<InstanceMinScreenSize>::default(),
+            ExtractResourcePlugin::<GpuCullMode>::default(),
+            ExtractResourcePlugin::<MeshBoundingSpheres>::default(),
+        ));
 
-        app.add_plugins((ExtractComponentPlugin::<GpuCullCompute>::default(),));
+        let readback = InstanceCountReadback::default();
+
+        app.init_resource::<GpuCullMode>()
+            .init_resource::<MeshBoundingSpheres>()
+            .insert_resource(readback.clone())
+            .register_diagnostic(Diagnostic::new(
+                InstanceCullDiagnostics::SUBMITTED_INSTANCES,
+            ))
+            .register_diagnostic(Diagnostic::new(InstanceCullDiagnostics::VISIBLE_INSTANCES))
+            .register_diagnostic(
+                Diagnostic::new(InstanceCullDiagnostics::CULLED_PERCENT).with_suffix("%"),
+            )
+            .add_systems(Update, update_visible_instance_counts)
+            .add_systems(PostUpdate, update_mesh_bounding_spheres);
 
         let render_app = app.sub_app_mut(RenderApp);
 
-        render_app.add_systems(
-            Render,
-            (
-                (queue_instanced_material_compute_pipeline,).in_set(RenderSystems::QueueMeshes),
+        render_app
+            .init_resource::<CullViewCount>()
+            .insert_resource(GlobalStableCompaction(self.stable_compaction))
+            .init_resource::<GpuCullMode>()
+            .init_resource::<MeshBoundingSpheres>()
+            .init_resource::<InstanceCullArena>()
+            .init_resource::<InstancedCullPipelines>()
+            .insert_resource(readback)
+            .init_resource::<PendingInstanceCountReadback>()
+            .add_systems(
+                Render,
                 (
-                    prepare_global_cull_buffer,
-                    prepare_instanced_material_compute_resources.after(prepare_global_cull_buffer),
-                )
-                    .in_set(RenderSystems::PrepareResources),
-            ),
-        );
+                    clear_instanced_cull_pipelines.in_set(RenderSystems::ExtractCommands),
+                    (
+                        specialize_instance_layout_cull_pipelines,
+                        prepare_instance_cull_arena,
+                    )
+                        .chain()
+                        .in_set(RenderSystems::PrepareMeshes),
+                    (
+                        queue_instanced_material_compute_pipeline,
+                        queue_depth_pyramid_pipelines,
+                    )
+                        .in_set(RenderSystems::QueueMeshes),
+                    (
+                        prepare_view_cull_buffers,
+                        prepare_depth_pyramid.after(prepare_view_cull_buffers),
+                        prepare_instanced_material_compute_resources
+                            .after(prepare_view_cull_buffers),
+                    )
+                        .in_set(RenderSystems::PrepareResources),
+                    prepare_instance_count_readback.in_set(RenderSystems::PrepareBindGroups),
+                    map_instance_count_readback
+                        .after(render_system)
+                        .in_set(RenderSystems::Render),
+                ),
+            );
 
         let compute_node = InstancedComputeNode::from_world(render_app.world_mut());
         let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
 
         render_graph.add_node(InstancedMaterialComputeLabel, compute_node);
-        render_graph.add_node_edge(InstancedMaterialComputeLabel, CameraDriverLabel);
+        render_graph.add_node(InstanceCountReadbackLabel, InstanceCountReadbackNode);
+        render_graph.add_node_edge(InstancedMaterialComputeLabel, InstanceCountReadbackLabel);
+        render_graph.add_node_edge(InstanceCountReadbackLabel, CameraDriverLabel);
+
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<DepthPyramidNode>>(
+                Core3d,
+                InstancedDepthPyramidLabel,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::EndPrepasses,
+                    InstancedDepthPyramidLabel,
+                    Node3d::StartMainPass,
+                ),
+            );
     }
 
     fn finish(&self, app: &mut App) {
         app.sub_app_mut(RenderApp)
-            .init_resource::<InstancedComputePipeline>();
+            .init_resource::<InstancedComputePipeline>()
+            .init_resource::<SpecializedComputePipelines<InstancedComputePipeline>>()
+            .init_resource::<DepthPyramidPipeline>();
     }
 }
@@ -1,86 +1,293 @@
-use crate::cull::pipeline::InstancedComputePipeline;
+use crate::cull::pipeline::{
+    DEPTH_PYRAMID_FORMAT, INSTANCE_VISIBILITY_SIZE, InstancedComputePipeline,
+};
 use crate::prelude::*;
+use crate::render::prepare::write_instance_changes;
 
-use bevy_camera::Camera;
+use bevy_asset::AssetId;
+use bevy_camera::primitives::Frustum;
+use bevy_core_pipeline::prepass::DepthPrepass;
 use bevy_ecs::prelude::*;
-use bevy_math::Vec4;
+use bevy_math::{Mat4, UVec2, Vec4};
+use bevy_mesh::Mesh;
 use bevy_pbr::RenderMeshInstances;
 use bevy_render::{
+    camera::ExtractedCamera,
     mesh::allocator::MeshAllocator,
     mesh::{RenderMesh, RenderMeshBufferInfo},
     render_asset::RenderAssets,
     render_resource::{
-        BindGroupEntry, BufferDescriptor, BufferInitDescriptor, BufferUsages,
-        DrawIndexedIndirectArgs,
+        BindGroupEntries, BindGroupEntry, BindingResource, Buffer, BufferBinding, BufferDescriptor,
+        BufferInitDescriptor, BufferUsages, DrawIndexedIndirectArgs, DrawIndirectArgs, Extent3d,
+        TextureDescriptor, TextureDimension, TextureUsages, TextureViewDescriptor,
     },
     renderer::{RenderDevice, RenderQueue},
+    settings::WgpuFeatures,
     sync_world::MainEntity,
     view::ExtractedView,
 };
 
 use bevy_transform::components::GlobalTransform;
+use bevy_utils::default;
 
 use bytemuck::bytes_of;
+use std::num::NonZeroU64;
+#[cfg(feature = "trace")]
 use tracing::warn;
 
-pub fn prepare_global_cull_buffer(
+/// Writes the [`CameraCullData`] of every camera view and assigns each view its index in the
+/// per-view output and indirect buffers.
+pub fn prepare_view_cull_buffers(
     mut commands: Commands,
-    views: Query<(&ExtractedView, &Camera)>,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        &ExtractedCamera,
+        Option<&InstancedDepthPyramid>,
+        Option<&ViewCullBuffer>,
+    )>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
-    global_buffer: Option<ResMut<GlobalCullBuffer>>,
+    mut view_count: ResMut<CullViewCount>,
     pipeline: Res<InstancedComputePipeline>,
 ) {
     if views.is_empty() {
         #[cfg(feature = "trace")]
         warn!("No active camera/view found for culling.");
-        return;
     }
 
-    let Some((view, _camera)) = views.iter().find(|(_, cam)| cam.is_active) else {
-        return;
-    };
+    // Only active cameras are extracted, sort them to keep the view indices stable.
+    let mut views: Vec<_> = views.iter().collect();
+    views.sort_by_key(|(entity, _, camera, ..)| (camera.order, *entity));
 
-    let camera_position = view.world_from_view.translation();
+    *view_count = CullViewCount(views.len() as u32);
 
-    let data = CameraCullData {
-        view_pos: Vec4::from((camera_position, 1.0)),
-    };
+    for (view_index, (entity, view, _camera, depth_pyramid, view_cull_buffer)) in
+        views.into_iter().enumerate()
+    {
+        let camera_position = view.world_from_view.translation();
+        let frustum = Frustum::from_clip_from_world(&clip_from_world(view));
 
-    let contents = bytes_of(&data);
+        let data = CameraCullData {
+            view_pos: Vec4::from((camera_position, 1.0)),
+            frustum: frustum.half_spaces.map(|half_space| half_space.normal_d()),
+            clip_from_view: view.clip_from_view,
+            viewport: view.viewport.as_vec4(),
+            occlusion_clip_from_world: depth_pyramid
+                .map(|depth_pyramid| depth_pyramid.clip_from_world)
+                .unwrap_or_default(),
+            occlusion_viewport: depth_pyramid
+                .map(|depth_pyramid| depth_pyramid.viewport)
+                .unwrap_or_default(),
+            depth_pyramid_mip_count: depth_pyramid
+                .map(|depth_pyramid| depth_pyramid.texture.mip_level_count())
+                .unwrap_or_default(),
+            view_index: view_index as u32,
+            ..default()
+        };
 
-    if let Some(global) = global_buffer {
-        render_queue.write_buffer(&global.buffer, 0, contents);
-    } else {
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("instanced_material_compute_global_cull_camera_buffer"),
-            contents,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
+        let contents = bytes_of(&data);
+
+        let buffer = if let Some(view_cull_buffer) = view_cull_buffer {
+            render_queue.write_buffer(&view_cull_buffer.buffer, 0, contents);
+            view_cull_buffer.buffer.clone()
+        } else {
+            render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("instanced_material_compute_view_cull_camera_buffer"),
+                contents,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            })
+        };
 
+        let depth_pyramid_view = depth_pyramid
+            .map(|depth_pyramid| &depth_pyramid.all_mips)
+            .unwrap_or(&pipeline.dummy_depth_pyramid);
+
+        // The depth pyramid is reallocated on resize, so the bind group is recreated every frame.
         let bind_group = render_device.create_bind_group(
-            "instanced_global_cull_bind_group",
+            "instanced_view_cull_bind_group",
             &pipeline.global_layout,
-            &[BindGroupEntry {
-                binding: 0,
-                resource: buffer.as_entire_binding(),
-            }],
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(depth_pyramid_view),
+                },
+            ],
         );
 
-        commands.insert_resource(GlobalCullBuffer { buffer, bind_group });
+        commands.entity(entity).insert(ViewCullBuffer {
+            buffer,
+            bind_group,
+            view_index: view_index as u32,
+        });
+    }
+}
+
+/// Allocates the [`InstancedDepthPyramid`] of views with a depth prepass, if any entity
+/// uses [`GpuOcclusionCull`].
+///
+/// Runs after [`prepare_view_cull_buffers`], so the cull pass still sees the matrices of the
+/// frame the pyramid was built in.
+pub fn prepare_depth_pyramid(
+    mut commands: Commands,
+    mut views: Query<
+        (
+            Entity,
+            &ExtractedView,
+            &ExtractedCamera,
+            Option<&mut InstancedDepthPyramid>,
+        ),
+        With<DepthPrepass>,
+    >,
+    occlusion_culled: Query<(), With<GpuOcclusionCull>>,
+    render_device: Res<RenderDevice>,
+) {
+    for (entity, view, camera, depth_pyramid) in &mut views {
+        if occlusion_culled.is_empty() {
+            if depth_pyramid.is_some() {
+                commands.entity(entity).remove::<InstancedDepthPyramid>();
+            }
+
+            continue;
+        }
+
+        let Some(depth_size) = camera.physical_target_size else {
+            continue;
+        };
+
+        let clip_from_world = clip_from_world(view);
+        let viewport = view.viewport.as_vec4();
+
+        if let Some(mut depth_pyramid) = depth_pyramid
+            && depth_pyramid.depth_size == depth_size
+        {
+            depth_pyramid.clip_from_world = clip_from_world;
+            depth_pyramid.viewport = viewport;
+            continue;
+        }
+
+        let size = depth_size.map(|x| x.div_ceil(2)).max(UVec2::ONE);
+        let mip_count = size.max_element().ilog2() + 1;
+
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("instanced_material_depth_pyramid"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: DEPTH_PYRAMID_FORMAT,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let all_mips = texture.create_view(&TextureViewDescriptor::default());
+        let mips = (0..mip_count)
+            .map(|mip| {
+                texture.create_view(&TextureViewDescriptor {
+                    label: Some("instanced_material_depth_pyramid_mip"),
+                    base_mip_level: mip,
+                    mip_level_count: Some(1),
+                    ..default()
+                })
+            })
+            .collect();
+
+        commands.entity(entity).insert(InstancedDepthPyramid {
+            texture,
+            all_mips,
+            mips,
+            depth_size,
+            clip_from_world,
+            viewport,
+        });
+    }
+}
+
+fn clip_from_world(view: &ExtractedView) -> Mat4 {
+    view.clip_from_world
+        .unwrap_or_else(|| view.clip_from_view * view.world_from_view.to_matrix().inverse())
+}
+
+/// Builds the [`LodCullData`] of an entity, together with the [`InstanceLods`] levels it uses.
+#[allow(clippy::too_many_arguments)]
+fn lod_cull_data<'a>(
+    instance_data: &InstanceMaterialData,
+    gtf: &GlobalTransform,
+    bounding_sphere: InstanceBoundingSphere,
+    lods: Option<&'a InstanceLods>,
+    density_falloff: Option<&InstanceDensityFalloff>,
+    min_screen_size: Option<&InstanceMinScreenSize>,
+    occlusion_cull: bool,
+    stable_compaction: bool,
+    sort: bool,
+) -> (LodCullData, &'a [InstanceLod]) {
+    let lods = lods.map_or(&[][..], |lods| {
+        &lods.0[..lods.0.len().min(MAX_INSTANCE_LODS)]
+    });
+
+    let mut lod_distances = [f32::MAX; MAX_INSTANCE_LODS];
+    for (distance, lod) in lod_distances.iter_mut().zip(lods) {
+        *distance = lod.distance;
     }
+
+    let mut flags = InstanceCullFlags::empty();
+    flags.set(InstanceCullFlags::OCCLUSION, occlusion_cull);
+    flags.set(InstanceCullFlags::STABLE_COMPACTION, stable_compaction);
+    flags.set(InstanceCullFlags::SORT, sort);
+    flags.set(
+        InstanceCullFlags::DENSITY_FALLOFF,
+        density_falloff.is_some(),
+    );
+    flags.set(
+        InstanceCullFlags::DENSITY_SCALE_COMPENSATION,
+        density_falloff.is_some_and(|falloff| falloff.compensate_scale),
+    );
+
+    let lod_data = LodCullData {
+        visibility_range: instance_data.visibility_range,
+        world_from_local: gtf.to_matrix(),
+        bounding_sphere: bounding_sphere.into(),
+        lod_distances: Vec4::from_array(lod_distances),
+        density_falloff: density_falloff.copied().map(Vec4::from).unwrap_or_default(),
+        min_screen_size: min_screen_size.map_or(0.0, |min_screen_size| min_screen_size.0),
+        flags: flags.bits(),
+        lod_count: lods.len() as u32 + 1,
+        ..default()
+    };
+
+    (lod_data, lods)
 }
 
 pub fn prepare_instanced_material_compute_resources(
     mut commands: Commands,
-    query: Query<
+    mut query: Query<
         (
             Entity,
             &MainEntity,
-            &InstanceMaterialData,
+            Ref<InstanceMaterialData>,
             &GlobalTransform,
-            Option<&InstancedComputeSourceBuffer>,
-            Option<&GpuDrawIndexedIndirect>,
+            Option<&InstanceBoundingSphere>,
+            Option<&InstanceLods>,
+            Option<&InstanceDensityFalloff>,
+            Option<&InstanceMinScreenSize>,
+            Has<GpuOcclusionCull>,
+            Has<GpuStableCompaction>,
+            Has<GpuInstanceSort>,
+            (
+                Option<&mut InstancedComputeSourceBuffer>,
+                Option<&InstanceBuffer>,
+                Option<&InstanceLodBuffer>,
+                Option<&GpuDrawIndexedIndirect>,
+                Option<&GpuDrawIndirect>,
+            ),
         ),
         With<GpuCullCompute>,
     >,
@@ -90,16 +297,99 @@ pub fn prepare_instanced_material_compute_resources(
     meshes: Res<RenderAssets<RenderMesh>>,
     mesh_allocator: Res<MeshAllocator>,
     pipeline: Res<InstancedComputePipeline>,
+    view_count: Res<CullViewCount>,
+    arena: Res<InstanceCullArena>,
+    global_stable_compaction: Res<GlobalStableCompaction>,
+    mesh_bounding_spheres: Res<MeshBoundingSpheres>,
 ) {
-    for (entity, main_entity, instance_data, gtf, existing_source, existing_indirect) in &query {
+    let view_count = **view_count;
+    if view_count == 0 {
+        // The changes are cleared in the main world after this frame, so they have to be caught
+        // up once the entities are prepared again.
+        for (_, _, instance_data, .., (existing_source, _, _, _, _)) in &mut query {
+            if instance_data.is_changed()
+                && let Some(mut existing) = existing_source
+            {
+                existing.stale = true;
+            }
+        }
+        return;
+    }
+
+    for (
+        entity,
+        main_entity,
+        instance_data,
+        gtf,
+        bounding_sphere,
+        lods,
+        density_falloff,
+        min_screen_size,
+        occlusion_cull,
+        stable_compaction,
+        sort,
+        (
+            mut existing_source,
+            existing_output,
+            existing_lod,
+            existing_indexed_indirect,
+            existing_indirect,
+        ),
+    ) in &mut query
+    {
         let count = instance_data.instances.len();
-        if count == 0 {
+        let layout = instance_data.instances.layout();
+        if count == 0 || arena.chunks.contains_key(&entity) {
             continue;
         }
 
-        if existing_source.is_some_and(|s| s.count == count as u32) {
-            if let Some(indirect) = existing_indirect {
-                render_queue.write_buffer(&indirect.buffer, 4, &[0, 0, 0, 0]);
+        let mesh_id = render_mesh_instances
+            .render_mesh_queue_data(*main_entity)
+            .map(|mesh_instance| mesh_instance.mesh_asset_id);
+
+        let (lod_data, lods) = lod_cull_data(
+            &instance_data,
+            gtf,
+            mesh_bounding_spheres.resolve(bounding_sphere, mesh_id),
+            lods,
+            density_falloff,
+            min_screen_size,
+            occlusion_cull,
+            stable_compaction || **global_stable_compaction,
+            sort,
+        );
+        let lod_count = lod_data.lod_count;
+        let stable_compaction = lod_data.flags & InstanceCullFlags::STABLE_COMPACTION.bits() != 0;
+
+        let contents = bytes_of(&lod_data);
+
+        if let Some(existing) = existing_source.as_mut()
+            && existing.count == count as u32
+            && existing.view_count == view_count
+            && existing.lod_count == lod_count
+            && existing.stable_compaction == stable_compaction
+            && existing.layout == layout
+        {
+            // Like `prepare_instance_buffer`, only upload the instances edited since the last
+            // extraction.
+            if existing.stale {
+                render_queue.write_buffer(&existing.buffer, 0, instance_data.instances.bytes());
+                existing.stale = false;
+            } else if instance_data.is_changed() {
+                write_instance_changes(&render_queue, &existing.buffer, &instance_data.instances);
+            }
+
+            if let Some(lod_buffer) = existing_lod {
+                render_queue.write_buffer(&lod_buffer.buffer, 0, contents);
+            }
+
+            let indirect = existing_indexed_indirect
+                .map(|indirect| (&indirect.buffer, indirect.offset))
+                .or(existing_indirect.map(|indirect| (&indirect.buffer, indirect.offset)));
+
+            // Resets the instance counts of all views and LOD levels with a single write.
+            if let Some((buffer, offset)) = indirect {
+                render_queue.write_buffer(buffer, offset, &existing.indirect_contents);
             }
 
             continue;
@@ -108,80 +398,171 @@ pub fn prepare_instanced_material_compute_resources(
         let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(*main_entity) else {
             continue;
         };
-        let Some(gpu_mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
-            continue;
-        };
-        let Some(vertex_slice) = mesh_allocator.mesh_vertex_slice(&mesh_instance.mesh_asset_id)
-        else {
-            continue;
-        };
 
-        let indirect_buffer = if let RenderMeshBufferInfo::Indexed {
-            count: index_count, ..
-        } = gpu_mesh.buffer_info
-        {
-            let Some(index_slice) = mesh_allocator.mesh_index_slice(&mesh_instance.mesh_asset_id)
-            else {
-                continue;
-            };
+        let mesh_ids: Vec<AssetId<Mesh>> = core::iter::once(mesh_instance.mesh_asset_id)
+            .chain(lods.iter().map(|lod| lod.mesh.id()))
+            .collect();
 
-            let command = DrawIndexedIndirectArgs {
-                index_count,
-                instance_count: 0,
-                first_index: index_slice.range.start,
-                base_vertex: vertex_slice.range.start as i32,
-                first_instance: 0,
+        let mut indexed = None;
+        let mut lod_contents = Vec::new();
+        let mut indirect_stride = 0;
+
+        for mesh_id in &mesh_ids {
+            let Some(gpu_mesh) = meshes.get(*mesh_id) else {
+                break;
+            };
+            let Some(vertex_slice) = mesh_allocator.mesh_vertex_slice(mesh_id) else {
+                break;
             };
 
-            render_device.create_buffer_with_data(&BufferInitDescriptor {
-                label: Some("instanced_material_compute_indirect_buffer"),
-                contents: command.as_bytes(),
-                usage: BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_DST,
-            })
-        } else {
+            let is_indexed = matches!(gpu_mesh.buffer_info, RenderMeshBufferInfo::Indexed { .. });
+            if *indexed.get_or_insert(is_indexed) != is_indexed {
+                #[cfg(feature = "trace")]
+                warn!(
+                    "InstanceLods of {:?} mix indexed and non-indexed meshes, skipping GPU culling.",
+                    main_entity
+                );
+                break;
+            }
+
+            match gpu_mesh.buffer_info {
+                RenderMeshBufferInfo::Indexed {
+                    count: index_count, ..
+                } => {
+                    let Some(index_slice) = mesh_allocator.mesh_index_slice(mesh_id) else {
+                        break;
+                    };
+
+                    let command = DrawIndexedIndirectArgs {
+                        index_count,
+                        instance_count: 0,
+                        first_index: index_slice.range.start,
+                        base_vertex: vertex_slice.range.start as i32,
+                        first_instance: 0,
+                    };
+
+                    lod_contents.extend_from_slice(command.as_bytes());
+                    indirect_stride = size_of::<DrawIndexedIndirectArgs>();
+                }
+                RenderMeshBufferInfo::NonIndexed => {
+                    let command = DrawIndirectArgs {
+                        vertex_count: vertex_slice.range.len() as u32,
+                        instance_count: 0,
+                        first_vertex: vertex_slice.range.start,
+                        first_instance: 0,
+                    };
+
+                    lod_contents.extend_from_slice(command.as_bytes());
+                    indirect_stride = size_of::<DrawIndirectArgs>();
+                }
+            }
+        }
+
+        // Every LOD level has to be ready before the entity can be culled.
+        let Some(indexed) = indexed else {
             continue;
         };
+        if lod_contents.len() != indirect_stride * mesh_ids.len() {
+            continue;
+        }
 
-        let lod_data = LodCullData {
-            visibility_range: instance_data.visibility_range,
-            world_from_local: gtf.to_matrix(),
+        let indirect_contents = lod_contents.repeat(view_count as usize);
+        let existing_indirect_buffer = if indexed {
+            existing_indexed_indirect.map(|indirect| &indirect.buffer)
+        } else {
+            existing_indirect.map(|indirect| &indirect.buffer)
+        };
+        // Sized exactly, the instance count readback copies the whole buffer.
+        let indirect_buffer = match existing_indirect_buffer {
+            Some(buffer) if buffer.size() == indirect_contents.len() as u64 => {
+                render_queue.write_buffer(buffer, 0, &indirect_contents);
+                buffer.clone()
+            }
+            _ => render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("instanced_material_compute_indirect_buffer"),
+                contents: &indirect_contents,
+                usage: BufferUsages::STORAGE
+                    | BufferUsages::INDIRECT
+                    | BufferUsages::COPY_DST
+                    | BufferUsages::COPY_SRC,
+            }),
         };
 
-        let contents = bytes_of(&lod_data);
+        let lod_buffer = match existing_lod {
+            Some(lod_buffer) => {
+                render_queue.write_buffer(&lod_buffer.buffer, 0, contents);
+                lod_buffer.buffer.clone()
+            }
+            None => render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("instanced_material_compute_lod_cull_data_buffer"),
+                contents,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            }),
+        };
 
-        let lod_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("instanced_material_compute_lod_cull_data_buffer"),
-            contents,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        let reused_source = existing_source
+            .as_deref()
+            .filter(|existing| existing.layout == layout && count as u32 <= existing.capacity);
+        let capacity = reused_source.map_or(instance_capacity(count) as u32, |existing| {
+            existing.capacity
         });
 
-        let source_buffer = if let Some(existing) = existing_source
-            && existing.count == count as u32
-        {
-            existing.buffer.clone()
-        } else {
-            render_device.create_buffer_with_data(&BufferInitDescriptor {
+        let source_buffer = match reused_source {
+            Some(existing) => existing.buffer.clone(),
+            None => render_device.create_buffer(&BufferDescriptor {
                 label: Some("instanced_material_compute_source_buffer"),
-                contents: bytemuck::cast_slice(&instance_data.instances),
-                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-            })
+                size: capacity as u64 * layout.size,
+                // Shadow views draw the source instances directly.
+                usage: BufferUsages::STORAGE | BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
         };
+        // Changes of frames in which the entity wasn't ready above are lost, upload everything.
+        render_queue.write_buffer(&source_buffer, 0, instance_data.instances.bytes());
 
-        let output_size = (count * size_of::<InstanceData>()) as u64;
-        let output_buffer = render_device.create_buffer(&BufferDescriptor {
-            label: Some("instanced_material_compute_output_buffer"),
-            size: output_size,
-            usage: BufferUsages::STORAGE | BufferUsages::VERTEX,
-            mapped_at_creation: false,
-        });
+        // Every view and LOD level gets its own range of instances.
+        let instance_stride = count as u64 * layout.size;
+        let output_size = instance_stride * (lod_count * view_count) as u64;
+        let output_buffer = match existing_output {
+            // Left over buffers of entities that weren't GPU culled can't be bound as storage.
+            Some(output)
+                if output.buffer.size() >= output_size
+                    && output.buffer.usage().contains(BufferUsages::STORAGE) =>
+            {
+                output.buffer.clone()
+            }
+            _ => render_device.create_buffer(&BufferDescriptor {
+                label: Some("instanced_material_compute_output_buffer"),
+                size: capacity as u64 * layout.size * (lod_count * view_count) as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::VERTEX,
+                mapped_at_creation: false,
+            }),
+        };
+
+        let visibility_buffer =
+            match reused_source.and_then(|existing| existing.visibility.as_ref()) {
+                _ if !stable_compaction => None,
+                Some(buffer) => Some(buffer.clone()),
+                None => Some(render_device.create_buffer(&BufferDescriptor {
+                    label: Some("instanced_material_compute_visibility_buffer"),
+                    size: capacity as u64 * INSTANCE_VISIBILITY_SIZE,
+                    usage: BufferUsages::STORAGE,
+                    mapped_at_creation: false,
+                })),
+            };
 
         let bind_group = render_device.create_bind_group(
             "instanced_material_compute_entity_bind_group",
             &pipeline.entity_layout, // Group 0 Layout
             &[
+                // The cull pass takes the instance count from the length of the binding.
                 BindGroupEntry {
                     binding: 0,
-                    resource: source_buffer.as_entire_binding(),
+                    resource: BindingResource::Buffer(BufferBinding {
+                        buffer: &source_buffer,
+                        offset: 0,
+                        size: NonZeroU64::new(instance_stride),
+                    }),
                 },
                 BindGroupEntry {
                     binding: 1,
@@ -195,24 +576,543 @@ pub fn prepare_instanced_material_compute_resources(
                     binding: 3,
                     resource: lod_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: visibility_buffer
+                        .as_ref()
+                        .unwrap_or(&pipeline.dummy_visibility)
+                        .as_entire_binding(),
+                },
             ],
         );
 
+        let indirect_stride = indirect_stride as u64;
+
         commands.entity(entity).insert((
             InstancedComputeSourceBuffer {
                 buffer: source_buffer,
                 count: count as u32,
+                capacity,
+                visibility: visibility_buffer,
+                view_count,
+                lod_count,
+                stable_compaction,
+                layout,
+                stale: false,
+                indirect_contents,
             },
             InstanceBuffer {
                 buffer: output_buffer,
                 length: 0,
-            },
-            GpuDrawIndexedIndirect {
-                buffer: indirect_buffer,
-                offset: 0,
+                capacity: capacity as usize,
+                view_stride: instance_stride * lod_count as u64,
+                lod_stride: instance_stride,
             },
             InstancedComputeBindGroup(bind_group),
             InstanceLodBuffer { buffer: lod_buffer },
         ));
+
+        if lod_count > 1 {
+            commands.entity(entity).insert(InstanceLodMeshes(mesh_ids));
+        } else {
+            commands.entity(entity).remove::<InstanceLodMeshes>();
+        }
+
+        if indexed {
+            commands
+                .entity(entity)
+                .remove::<GpuDrawIndirect>()
+                .insert(GpuDrawIndexedIndirect {
+                    buffer: indirect_buffer,
+                    offset: 0,
+                    view_stride: indirect_stride * lod_count as u64,
+                    lod_stride: indirect_stride,
+                });
+        } else {
+            commands
+                .entity(entity)
+                .remove::<GpuDrawIndexedIndirect>()
+                .insert(GpuDrawIndirect {
+                    buffer: indirect_buffer,
+                    offset: 0,
+                    view_stride: indirect_stride * lod_count as u64,
+                    lod_stride: indirect_stride,
+                });
+        }
+    }
+}
+
+/// Uploads the [`InstanceCullArena`] of [`GpuCullMode::Arena`].
+///
+/// Entities that can't be culled in the arena are left to
+/// [`prepare_instanced_material_compute_resources`].
+pub fn prepare_instance_cull_arena(
+    mut commands: Commands,
+    query: Query<
+        (
+            Entity,
+            &MainEntity,
+            Ref<InstanceMaterialData>,
+            &GlobalTransform,
+            Option<&InstanceBoundingSphere>,
+            Option<&InstanceLods>,
+            Option<&InstanceDensityFalloff>,
+            Option<&InstanceMinScreenSize>,
+            Has<GpuOcclusionCull>,
+            Has<GpuStableCompaction>,
+            Has<GpuInstanceSort>,
+            Has<InstancedComputeSourceBuffer>,
+            &PreparedInstanceUniforms,
+            Has<InstancePreviousData>,
+        ),
+        With<GpuCullCompute>,
+    >,
+    views: Query<(), (With<ExtractedView>, With<ExtractedCamera>)>,
+    mode: Res<GpuCullMode>,
+    global_stable_compaction: Res<GlobalStableCompaction>,
+    mut arena: ResMut<InstanceCullArena>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    meshes: Res<RenderAssets<RenderMesh>>,
+    mesh_allocator: Res<MeshAllocator>,
+    pipeline: Res<InstancedComputePipeline>,
+    cull_pipelines: Res<InstancedCullPipelines>,
+    mesh_bounding_spheres: Res<MeshBoundingSpheres>,
+) {
+    arena.chunks.clear();
+
+    let view_count = views.iter().count() as u32;
+
+    if *mode != GpuCullMode::Arena
+        || view_count == 0
+        || !render_device
+            .features()
+            .contains(WgpuFeatures::INDIRECT_FIRST_INSTANCE)
+        || !batched_instances_supported(&render_device)
+    {
+        arena.buffers = None;
+        return;
+    }
+
+    arena.multi_draw_indirect = render_device
+        .features()
+        .contains(WgpuFeatures::MULTI_DRAW_INDIRECT);
+
+    // Entities sharing mesh and material become neighbours and are drawn as one batch. The
+    // previous instances of the motion vector prepass are bound per entity, and blended entities
+    // are sorted per entity.
+    let mut entities: Vec<_> = query
+        .iter()
+        .map(|item| {
+            let (entity, main_entity, .., uniforms, previous_data) = &item;
+            let key = (
+                uniforms.material,
+                render_mesh_instances
+                    .render_mesh_queue_data(**main_entity)
+                    .map(|mesh_instance| mesh_instance.mesh_asset_id),
+                (*previous_data || uniforms.blend).then_some(*entity),
+            );
+            (key, item)
+        })
+        .collect();
+    entities.sort_by_key(|(key, _)| *key);
+
+    let mut instance_count_total = 0;
+    let mut sources = Vec::new();
+    let mut packing = Vec::new();
+    let mut chunks = Vec::new();
+    let mut indexed_draws = Vec::new();
+    let mut draws = Vec::new();
+    let mut output_count = 0;
+    let mut any_stable_compaction = false;
+    let mut batch = None;
+
+    for (
+        key,
+        (
+            entity,
+            main_entity,
+            instance_data,
+            gtf,
+            bounding_sphere,
+            lods,
+            density_falloff,
+            min_screen_size,
+            occlusion_cull,
+            stable_compaction,
+            sort,
+            has_entity_buffers,
+            uniforms,
+            _,
+        ),
+    ) in entities
+    {
+        let changed = instance_data.is_changed();
+        let instance_data = instance_data.into_inner();
+
+        // Custom cull shaders need their own dispatch, all instances of the arena share a layout.
+        let Some(entity_instances) = instance_data.instances.get::<InstanceData>() else {
+            continue;
+        };
+        let instance_count = entity_instances.len() as u32;
+        if instance_count == 0 || cull_pipelines.contains_key(&entity) {
+            continue;
+        }
+
+        let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(*main_entity) else {
+            continue;
+        };
+
+        let (mut lod_data, lods) = lod_cull_data(
+            instance_data,
+            gtf,
+            mesh_bounding_spheres.resolve(bounding_sphere, Some(mesh_instance.mesh_asset_id)),
+            lods,
+            density_falloff,
+            min_screen_size,
+            occlusion_cull,
+            stable_compaction || **global_stable_compaction,
+            sort,
+        );
+
+        let mesh_ids = core::iter::once(mesh_instance.mesh_asset_id)
+            .chain(lods.iter().map(|lod| lod.mesh.id()));
+
+        let mut buffers_key = None;
+        let mut indexed_args = Vec::new();
+        let mut args = Vec::new();
+
+        for (level, mesh_id) in mesh_ids.enumerate() {
+            let Some(gpu_mesh) = meshes.get(mesh_id) else {
+                break;
+            };
+            let Some(vertex_slice) = mesh_allocator.mesh_vertex_slice(&mesh_id) else {
+                break;
+            };
+
+            // All levels are drawn with one multi-draw, so they have to share the mesh buffers.
+            let key = (
+                mesh_allocator.mesh_slabs(&mesh_id),
+                match gpu_mesh.buffer_info {
+                    RenderMeshBufferInfo::Indexed { index_format, .. } => Some(index_format),
+                    RenderMeshBufferInfo::NonIndexed => None,
+                },
+            );
+            if *buffers_key.get_or_insert(key) != key {
+                break;
+            }
+
+            // Every LOD level has its own range of `instance_count` instances.
+            let first_instance = output_count + level as u32 * instance_count;
+
+            match gpu_mesh.buffer_info {
+                RenderMeshBufferInfo::Indexed {
+                    count: index_count, ..
+                } => {
+                    let Some(index_slice) = mesh_allocator.mesh_index_slice(&mesh_id) else {
+                        break;
+                    };
+
+                    indexed_args.push(DrawIndexedIndirectArgs {
+                        index_count,
+                        instance_count: 0,
+                        first_index: index_slice.range.start,
+                        base_vertex: vertex_slice.range.start as i32,
+                        first_instance,
+                    });
+                }
+                RenderMeshBufferInfo::NonIndexed => {
+                    args.push(DrawIndirectArgs {
+                        vertex_count: vertex_slice.range.len() as u32,
+                        instance_count: 0,
+                        first_vertex: vertex_slice.range.start,
+                        first_instance,
+                    });
+                }
+            }
+        }
+
+        // Every LOD level has to be ready before the entity can be culled.
+        let draw_count = lod_data.lod_count as usize;
+        let indexed = indexed_args.len() == draw_count;
+        if !indexed && args.len() != draw_count {
+            continue;
+        }
+
+        if has_entity_buffers {
+            commands.entity(entity).remove::<(
+                InstancedComputeSourceBuffer,
+                InstancedComputeBindGroup,
+                InstanceLodBuffer,
+                InstanceBuffer,
+                GpuDrawIndexedIndirect,
+                GpuDrawIndirect,
+                InstanceLodMeshes,
+            )>();
+        }
+
+        let first_draw = if indexed {
+            indexed_draws.extend(indexed_args);
+            indexed_draws.len() - draw_count
+        } else {
+            lod_data.flags |= InstanceCullFlags::NON_INDEXED.bits();
+            draws.extend(args);
+            draws.len() - draw_count
+        } as u32;
+
+        chunks.push(ArenaChunkData {
+            lod: lod_data,
+            first_instance: instance_count_total,
+            instance_count,
+            first_draw,
+            first_output: output_count,
+        });
+
+        // Draws of consecutive entities are consecutive as well, the leader of a batch draws them
+        // all.
+        let mut batch_draw_count = draw_count as u32;
+        match &batch {
+            Some((leader, leader_key)) if *leader_key == key => {
+                let leader = arena.chunks.get_mut(leader).unwrap();
+                if leader.indexed == indexed {
+                    leader.batch_draw_count += batch_draw_count;
+                    batch_draw_count = 0;
+                } else {
+                    batch = Some((entity, key));
+                }
+            }
+            _ => batch = Some((entity, key)),
+        }
+
+        arena.chunks.insert(
+            entity,
+            InstanceArenaChunk {
+                first_draw,
+                draw_count: draw_count as u32,
+                indexed,
+                first_instance: instance_count_total,
+                instance_count,
+                batch_draw_count,
+            },
+        );
+
+        sources.push((
+            instance_count_total,
+            entity_instances,
+            uniforms.index,
+            changed.then(|| instance_data.instances.changes()),
+        ));
+        packing.push((entity, instance_count, uniforms.index));
+        instance_count_total += instance_count;
+        output_count += instance_count * lod_data.lod_count;
+        any_stable_compaction |= lod_data.flags & InstanceCullFlags::STABLE_COMPACTION.bits() != 0;
+    }
+
+    if chunks.is_empty() {
+        arena.buffers = None;
+        return;
+    }
+
+    // Every view gets its own range of draws and instances.
+    let mut indexed_indirect = Vec::new();
+    let mut indirect = Vec::new();
+    for view_index in 0..view_count {
+        let view_offset = view_index * output_count;
+
+        for command in &indexed_draws {
+            let command = DrawIndexedIndirectArgs {
+                first_instance: command.first_instance + view_offset,
+                ..*command
+            };
+            indexed_indirect.extend_from_slice(command.as_bytes());
+        }
+        for command in &draws {
+            let command = DrawIndirectArgs {
+                first_instance: command.first_instance + view_offset,
+                ..*command
+            };
+            indirect.extend_from_slice(command.as_bytes());
+        }
+    }
+
+    // Empty bindings are invalid.
+    if indexed_indirect.is_empty() {
+        indexed_indirect.extend_from_slice(DrawIndexedIndirectArgs::default().as_bytes());
+    }
+    if indirect.is_empty() {
+        indirect.extend_from_slice(DrawIndirectArgs::default().as_bytes());
     }
+
+    let arena_data = ArenaCullData {
+        indexed_view_draw_stride: indexed_draws.len() as u32,
+        non_indexed_view_draw_stride: draws.len() as u32,
+        view_output_stride: output_count,
+        chunk_count: chunks.len() as u32,
+    };
+
+    let previous = arena.buffers.take();
+    let upload = |existing: Option<&Buffer>, label, contents: &[u8], usage| match existing {
+        Some(buffer) if buffer.size() == contents.len() as u64 => {
+            render_queue.write_buffer(buffer, 0, contents);
+            buffer.clone()
+        }
+        _ => render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some(label),
+            contents,
+            usage: usage | BufferUsages::COPY_DST,
+        }),
+    };
+
+    // The instance buffers grow like the ones of single entities, see `instance_capacity`.
+    let instance_size = size_of::<InstanceData>() as u64;
+    let source_size = instance_count_total as u64 * instance_size;
+    let reused_source = previous
+        .as_ref()
+        .filter(|buffers| buffers.source.size() >= source_size);
+    let source = match reused_source {
+        Some(buffers) => buffers.source.clone(),
+        None => render_device.create_buffer(&BufferDescriptor {
+            label: Some("instanced_material_arena_source_buffer"),
+            size: instance_capacity(instance_count_total as usize) as u64 * instance_size,
+            usage: BufferUsages::STORAGE | BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }),
+    };
+
+    // Batched draws select the uniforms of each instance's entity.
+    let pack = |instance: &InstanceData, uniform_index| InstanceData {
+        uniform_index,
+        ..*instance
+    };
+
+    // Chunks move whenever an entity joins or leaves the arena or its uniforms move, the
+    // instances are packed again then. Otherwise only the instances edited since the last
+    // extraction are uploaded, like in `prepare_instance_buffer`.
+    if reused_source.is_some_and(|buffers| buffers.packing == packing) {
+        for (first_instance, instances, uniform_index, changes) in sources {
+            let all = 0..instances.len();
+            let ranges = match changes {
+                None => continue,
+                Some(InstanceChanges::All) => std::slice::from_ref(&all),
+                Some(InstanceChanges::Ranges(ranges)) => ranges.as_slice(),
+            };
+            for range in ranges {
+                let packed: Vec<_> = instances[range.clone()]
+                    .iter()
+                    .map(|instance| pack(instance, uniform_index))
+                    .collect();
+                render_queue.write_buffer(
+                    &source,
+                    (first_instance as usize + range.start) as u64 * instance_size,
+                    bytemuck::cast_slice(&packed),
+                );
+            }
+        }
+    } else {
+        let packed: Vec<_> = sources
+            .into_iter()
+            .flat_map(|(_, instances, uniform_index, _)| {
+                instances
+                    .iter()
+                    .map(move |instance| pack(instance, uniform_index))
+            })
+            .collect();
+        render_queue.write_buffer(&source, 0, bytemuck::cast_slice(&packed));
+    }
+
+    let chunks_buffer = upload(
+        previous.as_ref().map(|buffers| &buffers.chunks),
+        "instanced_material_arena_chunk_buffer",
+        bytemuck::cast_slice(&chunks),
+        BufferUsages::STORAGE,
+    );
+    let arena_data_buffer = upload(
+        previous.as_ref().map(|buffers| &buffers.arena_data),
+        "instanced_material_arena_data_buffer",
+        bytes_of(&arena_data),
+        BufferUsages::UNIFORM,
+    );
+    // Rewriting the draws also resets the instance counts of the previous frame. They are copied
+    // out for the instance count readback.
+    let indexed_indirect_buffer = upload(
+        previous.as_ref().map(|buffers| &buffers.indexed_indirect),
+        "instanced_material_arena_indexed_indirect_buffer",
+        &indexed_indirect,
+        BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_SRC,
+    );
+    let indirect_buffer = upload(
+        previous.as_ref().map(|buffers| &buffers.indirect),
+        "instanced_material_arena_indirect_buffer",
+        &indirect,
+        BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_SRC,
+    );
+
+    let view_output_count = (output_count * view_count) as usize;
+    let output = match previous.as_ref() {
+        Some(buffers) if buffers.output.size() >= view_output_count as u64 * instance_size => {
+            buffers.output.clone()
+        }
+        _ => render_device.create_buffer(&BufferDescriptor {
+            label: Some("instanced_material_arena_output_buffer"),
+            size: instance_capacity(view_output_count) as u64 * instance_size,
+            usage: BufferUsages::STORAGE | BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        }),
+    };
+
+    let visibility_size = instance_count_total as u64 * INSTANCE_VISIBILITY_SIZE;
+    let visibility = match previous
+        .as_ref()
+        .and_then(|buffers| buffers.visibility.as_ref())
+    {
+        _ if !any_stable_compaction => None,
+        Some(buffer) if buffer.size() >= visibility_size => Some(buffer.clone()),
+        _ => Some(render_device.create_buffer(&BufferDescriptor {
+            label: Some("instanced_material_arena_visibility_buffer"),
+            size: instance_capacity(instance_count_total as usize) as u64
+                * INSTANCE_VISIBILITY_SIZE,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })),
+    };
+
+    let bind_group = render_device.create_bind_group(
+        "instanced_material_compute_arena_bind_group",
+        &pipeline.arena_layout,
+        &BindGroupEntries::sequential((
+            // The cull pass takes the instance count from the length of the binding.
+            BufferBinding {
+                buffer: &source,
+                offset: 0,
+                size: NonZeroU64::new(source_size),
+            },
+            output.as_entire_binding(),
+            indexed_indirect_buffer.as_entire_binding(),
+            chunks_buffer.as_entire_binding(),
+            arena_data_buffer.as_entire_binding(),
+            indirect_buffer.as_entire_binding(),
+            visibility
+                .as_ref()
+                .unwrap_or(&pipeline.dummy_visibility)
+                .as_entire_binding(),
+        )),
+    );
+
+    arena.buffers = Some(InstanceArenaBuffers {
+        source,
+        chunks: chunks_buffer,
+        arena_data: arena_data_buffer,
+        output,
+        indexed_indirect: indexed_indirect_buffer,
+        indirect: indirect_buffer,
+        visibility,
+        bind_group,
+        instance_count: instance_count_total,
+        packing,
+        chunk_count: chunks.len() as u32,
+        view_count,
+        indexed_view_draw_stride: arena_data.indexed_view_draw_stride,
+        non_indexed_view_draw_stride: arena_data.non_indexed_view_draw_stride,
+    });
 }
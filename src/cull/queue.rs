@@ -1,8 +1,20 @@
-use bevy_ecs::change_detection::{Res, ResMut};
-use bevy_render::render_resource::{ComputePipelineDescriptor, PipelineCache};
+use bevy_ecs::prelude::*;
+use bevy_render::{
+    render_asset::RenderAssets,
+    render_resource::{
+        BindGroupLayout, ComputePipelineDescriptor, PipelineCache, SpecializedComputePipelines,
+    },
+};
 use bevy_utils::default;
 
-use crate::cull::pipeline::InstancedComputePipeline;
+use crate::cull::pipeline::{
+    DepthPyramidPipeline, InstancedComputePipeline, InstancedCullPipelineFlags,
+    InstancedCullPipelineKey, InstancedMaterialCullPipeline, InstancedMaterialCullPipelineKey,
+};
+use crate::prelude::*;
+use crate::render::prepared_material::PreparedInstancedMaterial;
+
+use std::hash::Hash;
 
 pub fn queue_instanced_material_compute_pipeline(
     pipeline_cache: Res<PipelineCache>,
@@ -12,18 +24,199 @@ pub fn queue_instanced_material_compute_pipeline(
         return;
     }
 
-    let id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-        label: Some("instanced_material_compute_pipeline".into()),
-        layout: vec![
-            compute_pipeline.entity_layout.clone(),
-            compute_pipeline.global_layout.clone(),
-        ],
-        push_constant_ranges: vec![],
-        shader: compute_pipeline.shader.clone(),
-        shader_defs: vec![],
-        entry_point: Some("main".into()),
-        ..default()
-    });
+    let queue = |label: &'static str,
+                 entity_layout: &BindGroupLayout,
+                 shader_defs: Vec<_>,
+                 entry_point: &'static str| {
+        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some(label.into()),
+            layout: vec![
+                entity_layout.clone(),
+                compute_pipeline.global_layout.clone(),
+            ],
+            push_constant_ranges: vec![],
+            shader: compute_pipeline.shader.clone(),
+            shader_defs,
+            entry_point: Some(entry_point.into()),
+            ..default()
+        })
+    };
+
+    let entity_layout = &compute_pipeline.entity_layout;
+    let arena_layout = &compute_pipeline.arena_layout;
+    let non_indexed = || vec!["NON_INDEXED".into()];
+    let arena = || vec!["ARENA".into()];
+    let id = queue(
+        "instanced_material_compute_pipeline",
+        entity_layout,
+        vec![],
+        "main",
+    );
+    let non_indexed_id = queue(
+        "instanced_material_compute_non_indexed_pipeline",
+        entity_layout,
+        non_indexed(),
+        "main",
+    );
+    let arena_id = queue(
+        "instanced_material_compute_arena_pipeline",
+        arena_layout,
+        arena(),
+        "main",
+    );
+    let compact_id = queue(
+        "instanced_material_compact_pipeline",
+        entity_layout,
+        vec![],
+        "compact",
+    );
+    let non_indexed_compact_id = queue(
+        "instanced_material_compact_non_indexed_pipeline",
+        entity_layout,
+        non_indexed(),
+        "compact",
+    );
+    let arena_compact_id = queue(
+        "instanced_material_compact_arena_pipeline",
+        arena_layout,
+        arena(),
+        "compact",
+    );
 
     compute_pipeline.pipeline_id = Some(id);
+    compute_pipeline.non_indexed_pipeline_id = Some(non_indexed_id);
+    compute_pipeline.arena_pipeline_id = Some(arena_id);
+    compute_pipeline.compact_pipeline_id = Some(compact_id);
+    compute_pipeline.non_indexed_compact_pipeline_id = Some(non_indexed_compact_id);
+    compute_pipeline.arena_compact_pipeline_id = Some(arena_compact_id);
+}
+
+pub fn queue_depth_pyramid_pipelines(
+    pipeline_cache: Res<PipelineCache>,
+    mut depth_pyramid_pipeline: ResMut<DepthPyramidPipeline>,
+) {
+    if depth_pyramid_pipeline.downsample_pipeline_id.is_some() {
+        return;
+    }
+
+    let shader = depth_pyramid_pipeline.shader.clone();
+
+    let queue = |label: &'static str, layout: BindGroupLayout, shader_defs: Vec<_>| {
+        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some(label.into()),
+            layout: vec![layout],
+            push_constant_ranges: vec![],
+            shader: shader.clone(),
+            shader_defs,
+            entry_point: Some("main".into()),
+            ..default()
+        })
+    };
+
+    let first = queue(
+        "instanced_material_depth_pyramid_first_pipeline",
+        depth_pyramid_pipeline.first_layout.clone(),
+        vec!["FIRST_MIP".into()],
+    );
+    let first_multisampled = queue(
+        "instanced_material_depth_pyramid_first_multisampled_pipeline",
+        depth_pyramid_pipeline.first_multisampled_layout.clone(),
+        vec!["FIRST_MIP".into(), "MULTISAMPLED".into()],
+    );
+    let downsample = queue(
+        "instanced_material_depth_pyramid_downsample_pipeline",
+        depth_pyramid_pipeline.downsample_layout.clone(),
+        vec![],
+    );
+
+    depth_pyramid_pipeline.first_pipeline_id = Some(first);
+    depth_pyramid_pipeline.first_multisampled_pipeline_id = Some(first_multisampled);
+    depth_pyramid_pipeline.downsample_pipeline_id = Some(downsample);
+}
+
+pub fn clear_instanced_cull_pipelines(mut cull_pipelines: ResMut<InstancedCullPipelines>) {
+    cull_pipelines.clear();
+}
+
+/// Specializes the cull pipelines of every GPU culled entity whose material has a custom
+/// [`InstancedMaterial::cull_shader`].
+pub(crate) fn specialize_instanced_material_cull_pipelines<M>(
+    query: Query<(Entity, &InstancedMeshMaterial<M>, &InstanceMaterialData), With<GpuCullCompute>>,
+    render_materials: Res<RenderAssets<PreparedInstancedMaterial<M>>>,
+    cull_pipeline: Res<InstancedMaterialCullPipeline<M>>,
+    mut pipelines: ResMut<SpecializedComputePipelines<InstancedMaterialCullPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    mut cull_pipelines: ResMut<InstancedCullPipelines>,
+) where
+    M: InstancedMaterial,
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    for (entity, h_material, instance_data) in &query {
+        let Some(prepared_material) = render_materials.get(&h_material.0) else {
+            continue;
+        };
+        let Some(material_bind_group) = prepared_material.cull_bind_group.clone() else {
+            continue;
+        };
+
+        let mut specialize = |flags| {
+            let key = InstancedMaterialCullPipelineKey {
+                flags,
+                instance_layout: instance_data.instances.layout(),
+                bind_group_data: prepared_material.key.clone(),
+            };
+            pipelines.specialize(&pipeline_cache, &cull_pipeline, key)
+        };
+
+        cull_pipelines.insert(
+            entity,
+            InstancedMaterialCullPipelines {
+                pipeline_id: specialize(InstancedCullPipelineFlags::empty()),
+                non_indexed_pipeline_id: specialize(InstancedCullPipelineFlags::NON_INDEXED),
+                compact_pipeline_id: specialize(InstancedCullPipelineFlags::COMPACT),
+                non_indexed_compact_pipeline_id: specialize(
+                    InstancedCullPipelineFlags::NON_INDEXED | InstancedCullPipelineFlags::COMPACT,
+                ),
+                material_bind_group: Some(material_bind_group),
+            },
+        );
+    }
+}
+
+/// Specializes the default cull pipelines of every GPU culled entity with a non-default
+/// [`InstanceLayout`], unless its material has a custom cull shader.
+pub fn specialize_instance_layout_cull_pipelines(
+    query: Query<(Entity, &InstanceMaterialData), With<GpuCullCompute>>,
+    compute_pipeline: Res<InstancedComputePipeline>,
+    mut pipelines: ResMut<SpecializedComputePipelines<InstancedComputePipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    mut cull_pipelines: ResMut<InstancedCullPipelines>,
+) {
+    for (entity, instance_data) in &query {
+        let instance_layout = instance_data.instances.layout();
+        if instance_layout == InstanceLayout::default() || cull_pipelines.contains_key(&entity) {
+            continue;
+        }
+
+        let mut specialize = |flags| {
+            let key = InstancedCullPipelineKey {
+                flags,
+                instance_layout,
+            };
+            pipelines.specialize(&pipeline_cache, &compute_pipeline, key)
+        };
+
+        cull_pipelines.insert(
+            entity,
+            InstancedMaterialCullPipelines {
+                pipeline_id: specialize(InstancedCullPipelineFlags::empty()),
+                non_indexed_pipeline_id: specialize(InstancedCullPipelineFlags::NON_INDEXED),
+                compact_pipeline_id: specialize(InstancedCullPipelineFlags::COMPACT),
+                non_indexed_compact_pipeline_id: specialize(
+                    InstancedCullPipelineFlags::NON_INDEXED | InstancedCullPipelineFlags::COMPACT,
+                ),
+                material_bind_group: None,
+            },
+        );
+    }
 }
@@ -0,0 +1,289 @@
+use crate::prelude::*;
+
+use bevy_diagnostic::{DiagnosticPath, Diagnostics};
+use bevy_ecs::{entity::EntityHashMap, prelude::*};
+use bevy_render::{
+    render_resource::{
+        Buffer, BufferDescriptor, BufferUsages, DrawIndexedIndirectArgs, DrawIndirectArgs, MapMode,
+    },
+    renderer::RenderDevice,
+    sync_world::MainEntity,
+};
+
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "trace")]
+use tracing::warn;
+
+/// Maximum number of readbacks waiting for the GPU, further frames are skipped.
+const MAX_READBACKS_IN_FLIGHT: usize = 3;
+
+/// Totals of the GPU cull pass over all entities and views.
+pub struct InstanceCullDiagnostics;
+
+impl InstanceCullDiagnostics {
+    /// Number of instances tested in the cull pass, once per view.
+    pub const SUBMITTED_INSTANCES: DiagnosticPath =
+        DiagnosticPath::const_new("instanced_material/submitted_instances");
+    /// Number of instances that survived the cull pass, summed over all views and LOD levels.
+    pub const VISIBLE_INSTANCES: DiagnosticPath =
+        DiagnosticPath::const_new("instanced_material/visible_instances");
+    /// Percentage of the submitted instances that were culled.
+    pub const CULLED_PERCENT: DiagnosticPath =
+        DiagnosticPath::const_new("instanced_material/culled_percent");
+}
+
+/// Instance counts read back from the GPU, shared between the main and the render world.
+#[derive(Resource, Clone, Default)]
+pub struct InstanceCountReadback(Arc<Mutex<InstanceCountReadbackState>>);
+
+#[derive(Default)]
+struct InstanceCountReadbackState {
+    /// Counts of the latest finished readback, taken by [`update_visible_instance_counts`].
+    counts: Option<Vec<InstanceCount>>,
+    /// Staging buffers that are no longer mapped.
+    free: Vec<Buffer>,
+}
+
+#[derive(Clone, Copy)]
+struct InstanceCount {
+    main_entity: Entity,
+    submitted: u32,
+    visible: u32,
+}
+
+/// Where the `instance_count`s of an entity's draws end up in the staging buffer.
+struct InstanceCountReadbackEntry {
+    main_entity: Entity,
+    submitted: u32,
+    /// Byte offsets of the `instance_count` of every draw of every view.
+    offsets: Vec<u64>,
+}
+
+/// Copies of the indirect draw arguments of this frame, issued by
+/// [`InstanceCountReadbackNode`](crate::cull::node::InstanceCountReadbackNode).
+#[derive(Resource, Default)]
+pub struct PendingInstanceCountReadback {
+    pub(crate) readback: Option<InstanceCountReadbackRequest>,
+    /// Number of staging buffers, mapped or not.
+    allocated: usize,
+}
+
+pub(crate) struct InstanceCountReadbackRequest {
+    pub staging: Buffer,
+    /// Indirect buffers with their byte offset in the staging buffer.
+    pub copies: Vec<(Buffer, u64)>,
+    entries: Vec<InstanceCountReadbackEntry>,
+}
+
+/// Collects the indirect buffers of every GPU culled entity and the arena into a staging buffer
+/// copy, unless too many readbacks are still waiting for the GPU.
+pub fn prepare_instance_count_readback(
+    query: Query<
+        (
+            &MainEntity,
+            &InstancedComputeSourceBuffer,
+            Option<&GpuDrawIndexedIndirect>,
+            Option<&GpuDrawIndirect>,
+        ),
+        With<GpuCullCompute>,
+    >,
+    arena_query: Query<(&MainEntity, &InstanceMaterialData)>,
+    arena: Res<InstanceCullArena>,
+    shared: Res<InstanceCountReadback>,
+    mut pending: ResMut<PendingInstanceCountReadback>,
+    render_device: Res<RenderDevice>,
+) {
+    pending.readback = None;
+
+    let mut copies = Vec::new();
+    let mut entries = Vec::new();
+    let mut size = 0;
+
+    // `instance_count` is the second field of both indirect argument layouts.
+    for (main_entity, source, indexed_indirect, indirect) in &query {
+        let indirect = indexed_indirect
+            .map(|indirect| (&indirect.buffer, indirect.offset, indirect.lod_stride))
+            .or(indirect.map(|indirect| (&indirect.buffer, indirect.offset, indirect.lod_stride)));
+        let Some((buffer, offset, lod_stride)) = indirect else {
+            continue;
+        };
+
+        let draw_count = (source.view_count * source.lod_count) as u64;
+        entries.push(InstanceCountReadbackEntry {
+            main_entity: main_entity.id(),
+            submitted: source.count * source.view_count,
+            offsets: (0..draw_count)
+                .map(|draw_index| size + offset + lod_stride * draw_index + 4)
+                .collect(),
+        });
+
+        copies.push((buffer.clone(), size));
+        size += buffer.size();
+    }
+
+    if let Some(buffers) = &arena.buffers {
+        let indexed_base = size;
+        let base = indexed_base + buffers.indexed_indirect.size();
+
+        for (entity, chunk) in &arena.chunks {
+            let Ok((main_entity, instance_data)) = arena_query.get(*entity) else {
+                continue;
+            };
+
+            let (base, view_stride, draw_size) = if chunk.indexed {
+                (
+                    indexed_base,
+                    buffers.indexed_view_draw_stride,
+                    size_of::<DrawIndexedIndirectArgs>(),
+                )
+            } else {
+                (
+                    base,
+                    buffers.non_indexed_view_draw_stride,
+                    size_of::<DrawIndirectArgs>(),
+                )
+            };
+
+            entries.push(InstanceCountReadbackEntry {
+                main_entity: main_entity.id(),
+                submitted: instance_data.instances.len() as u32 * buffers.view_count,
+                offsets: (0..buffers.view_count)
+                    .flat_map(|view_index| {
+                        let first_draw = view_index * view_stride + chunk.first_draw;
+                        first_draw..first_draw + chunk.draw_count
+                    })
+                    .map(|draw_index| base + (draw_index as usize * draw_size) as u64 + 4)
+                    .collect(),
+            });
+        }
+
+        copies.push((buffers.indexed_indirect.clone(), indexed_base));
+        copies.push((buffers.indirect.clone(), base));
+        size = base + buffers.indirect.size();
+    }
+
+    if entries.is_empty() {
+        return;
+    }
+
+    let free = shared.0.lock().unwrap().free.pop();
+    let staging = match free {
+        Some(buffer) if buffer.size() >= size => buffer,
+        // Too small, replaced by a new buffer.
+        Some(_) => create_staging_buffer(&render_device, size),
+        None if pending.allocated < MAX_READBACKS_IN_FLIGHT => {
+            pending.allocated += 1;
+            create_staging_buffer(&render_device, size)
+        }
+        None => return,
+    };
+
+    pending.readback = Some(InstanceCountReadbackRequest {
+        staging,
+        copies,
+        entries,
+    });
+}
+
+fn create_staging_buffer(render_device: &RenderDevice, size: u64) -> Buffer {
+    render_device.create_buffer(&BufferDescriptor {
+        label: Some("instanced_material_instance_count_readback_buffer"),
+        size,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Maps the staging buffer of this frame once the GPU is done with it.
+///
+/// Runs after the render graph was submitted, the counts are picked up by
+/// [`update_visible_instance_counts`] whenever the mapping finishes.
+pub fn map_instance_count_readback(
+    mut pending: ResMut<PendingInstanceCountReadback>,
+    shared: Res<InstanceCountReadback>,
+) {
+    let Some(InstanceCountReadbackRequest {
+        staging, entries, ..
+    }) = pending.readback.take()
+    else {
+        return;
+    };
+
+    let shared = shared.clone();
+    let buffer = staging.clone();
+
+    staging.slice(..).map_async(MapMode::Read, move |result| {
+        let mut state = shared.0.lock().unwrap();
+
+        if let Err(_err) = result {
+            #[cfg(feature = "trace")]
+            warn!("Failed to map instance count readback buffer: {:?}", _err);
+            state.free.push(buffer);
+            return;
+        }
+
+        let data = buffer.slice(..).get_mapped_range();
+        let read = |offset: u64| {
+            let offset = offset as usize;
+            u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+        };
+
+        let counts = entries
+            .iter()
+            .map(|entry| InstanceCount {
+                main_entity: entry.main_entity,
+                submitted: entry.submitted,
+                visible: entry.offsets.iter().map(|offset| read(*offset)).sum(),
+            })
+            .collect();
+
+        drop(data);
+        buffer.unmap();
+
+        state.counts = Some(counts);
+        state.free.push(buffer);
+    });
+}
+
+/// Writes the latest readback into [`VisibleInstanceCount`] and the
+/// [`InstanceCullDiagnostics`].
+pub fn update_visible_instance_counts(
+    mut query: Query<(Entity, &mut VisibleInstanceCount)>,
+    shared: Res<InstanceCountReadback>,
+    mut diagnostics: Diagnostics,
+) {
+    let Some(counts) = shared.0.lock().unwrap().counts.take() else {
+        return;
+    };
+
+    let mut submitted = 0u64;
+    let mut visible = 0u64;
+    let mut visible_counts = EntityHashMap::default();
+
+    for count in counts {
+        submitted += count.submitted as u64;
+        visible += count.visible as u64;
+        visible_counts.insert(count.main_entity, count.visible);
+    }
+
+    for (entity, mut visible_count) in &mut query {
+        visible_count.set_if_neq(VisibleInstanceCount(
+            visible_counts.get(&entity).copied().unwrap_or_default(),
+        ));
+    }
+
+    diagnostics.add_measurement(&InstanceCullDiagnostics::SUBMITTED_INSTANCES, || {
+        submitted as f64
+    });
+    diagnostics.add_measurement(&InstanceCullDiagnostics::VISIBLE_INSTANCES, || {
+        visible as f64
+    });
+    diagnostics.add_measurement(&InstanceCullDiagnostics::CULLED_PERCENT, || {
+        if submitted == 0 {
+            0.0
+        } else {
+            100.0 * (1.0 - visible as f64 / submitted as f64)
+        }
+    });
+}
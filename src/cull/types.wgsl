@@ -1,10 +1,33 @@
 #define_import_path bevy_eidolon::cull::types
 
+#ifdef ORIENTED_INSTANCES
+struct InstanceData {
+    position: vec3<f32>,
+    index: u32,
+    rotation: vec4<f32>,
+    scale: vec3<f32>,
+    // Packed `Unorm8x4`.
+    color: u32,
+#ifdef INSTANCE_EXTRA_WORDS
+    // Fields of a custom `InstanceType`, copied as is.
+    extra: array<u32, #{INSTANCE_EXTRA_WORDS}>,
+#endif
+}
+#else
 struct InstanceData {
     pos_and_scale: vec4<f32>,
     rotation: f32,
     index: u32,
+    // Packed `Unorm8x4`.
+    color: u32,
+    // `InstanceData::uniform_index`, see `BATCHED_INSTANCES`.
+    uniform_index: u32,
+#ifdef INSTANCE_EXTRA_WORDS
+    // Fields of a custom `InstanceType`, copied as is.
+    extra: array<u32, #{INSTANCE_EXTRA_WORDS}>,
+#endif
 }
+#endif
 
 struct DrawIndexedIndirectArgs {
     index_count: u32,
@@ -14,11 +37,63 @@ struct DrawIndexedIndirectArgs {
     first_instance: u32,
 }
 
+struct DrawIndirectArgs {
+    vertex_count: u32,
+    instance_count: atomic<u32>,
+    first_vertex: u32,
+    first_instance: u32,
+}
+
 struct CameraCullData {
     view_pos: vec4<f32>,
+    frustum: array<vec4<f32>, 6>,
+    clip_from_view: mat4x4<f32>,
+    viewport: vec4<f32>,
+    occlusion_clip_from_world: mat4x4<f32>,
+    occlusion_viewport: vec4<f32>,
+    depth_pyramid_mip_count: u32,
+    view_index: u32,
 }
 
 struct LodCullData {
     visibility_range: vec4<f32>,
     world_from_local: mat4x4<f32>,
+    bounding_sphere: vec4<f32>,
+    lod_distances: vec4<f32>,
+    density_falloff: vec4<f32>,
+    flags: u32,
+    lod_count: u32,
+    min_screen_size: f32,
+}
+
+// Written by the cull pass for stable compaction.
+struct InstanceVisibility {
+    // LOD level of a visible instance, `INSTANCE_CULLED` otherwise.
+    lod: u32,
+    // Factor of the instance scale from density compensation.
+    scale_factor: f32,
+}
+
+const INSTANCE_CULLED: u32 = 0xffffffffu;
+
+struct ArenaChunkData {
+    lod: LodCullData,
+    first_instance: u32,
+    instance_count: u32,
+    first_draw: u32,
+    first_output: u32,
+}
+
+struct ArenaCullData {
+    indexed_view_draw_stride: u32,
+    non_indexed_view_draw_stride: u32,
+    view_output_stride: u32,
+    chunk_count: u32,
 }
+
+const INSTANCE_CULL_FLAGS_OCCLUSION: u32 = 1u;
+const INSTANCE_CULL_FLAGS_DENSITY_FALLOFF: u32 = 2u;
+const INSTANCE_CULL_FLAGS_DENSITY_SCALE_COMPENSATION: u32 = 4u;
+const INSTANCE_CULL_FLAGS_NON_INDEXED: u32 = 8u;
+const INSTANCE_CULL_FLAGS_STABLE_COMPACTION: u32 = 16u;
+const INSTANCE_CULL_FLAGS_SORT: u32 = 32u;
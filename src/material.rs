@@ -1,16 +1,18 @@
 use bevy_asset::{Asset, Handle};
 use bevy_color::{Color, ColorToComponents};
-use bevy_ecs::{prelude::*, query::QueryItem};
+use bevy_ecs::prelude::*;
 use bevy_math::Vec4;
 use bevy_mesh::MeshVertexBufferLayoutRef;
+use bevy_pbr::MeshPipelineKey;
 use bevy_reflect::TypePath;
 use bevy_render::{
+    Extract,
     batching::NoAutomaticBatching,
-    render_resource::{AsBindGroup, RenderPipelineDescriptor, SpecializedMeshPipelineError},
-    {
-        extract_component::ExtractComponent,
-        render_resource::{PolygonMode, ShaderType},
+    render_resource::{
+        AsBindGroup, ComputePipelineDescriptor, PolygonMode, RenderPipelineDescriptor, ShaderType,
+        SpecializedMeshPipelineError,
     },
+    sync_world::RenderEntity,
 };
 use bevy_shader::ShaderRef;
 use bitflags::bitflags;
@@ -21,6 +23,10 @@ use std::hash::Hash;
 
 pub trait InstancedMaterial: Asset + AsBindGroup + Clone + Sized + Send + Sync + 'static {
     /// The vertex shader.
+    ///
+    /// Batched draws of `GpuCullMode::Arena` define `BATCHED_INSTANCES`, custom shaders then
+    /// have to call `load_instance_uniforms` from `bevy_eidolon::render::bindings` before
+    /// reading `instance_uniforms`, in every vertex and fragment entry point.
     fn vertex_shader() -> ShaderRef {
         ShaderRef::Default
     }
@@ -30,6 +36,20 @@ pub trait InstancedMaterial: Asset + AsBindGroup + Clone + Sized + Send + Sync +
         ShaderRef::Default
     }
 
+    /// The vertex shader of the camera prepasses and shadow views.
+    ///
+    /// Custom vertex shaders that displace vertices need a matching one to keep the depth,
+    /// normals and shadows in place.
+    fn prepass_vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// The fragment shader of the camera prepasses, writes the normal and motion vector targets.
+    /// Shadow views don't use a fragment shader.
+    fn prepass_fragment_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
     fn polygon_mode(&self) -> PolygonMode {
         PolygonMode::Fill
     }
@@ -50,7 +70,23 @@ pub trait InstancedMaterial: Asset + AsBindGroup + Clone + Sized + Send + Sync +
         false
     }
 
+    /// Whether entities with this material are drawn into shadow maps.
+    fn casts_shadows(&self) -> bool {
+        true
+    }
+
+    /// Alpha masked materials are drawn in `AlphaMask3d`, blended ones in `Transparent3d`.
+    ///
+    /// Custom fragment shaders have to discard the masked fragments themselves, including the
+    /// [`InstancedMaterial::prepass_fragment_shader`] that is used for the prepass and shadows.
+    fn alpha_mode(&self) -> InstancedAlphaMode {
+        InstancedAlphaMode::Opaque
+    }
+
     /// Allow specializing the pipeline (e.g. enabling shader defs based on material settings).
+    ///
+    /// Also called for the prepass and shadow pipelines. Shadow views of materials that don't
+    /// discard are depth-only, their `descriptor.fragment` is `None`.
     fn specialize(
         _descriptor: &mut RenderPipelineDescriptor,
         _layout: &MeshVertexBufferLayoutRef,
@@ -58,6 +94,21 @@ pub trait InstancedMaterial: Asset + AsBindGroup + Clone + Sized + Send + Sync +
     ) -> Result<(), SpecializedMeshPipelineError> {
         Ok(())
     }
+
+    /// The compute shader of the GPU cull pass, needs a `main` and a `compact` entry point.
+    ///
+    /// Custom shaders can import the cull bindings from `bevy_eidolon::cull::bindings` and the
+    /// tests and helpers of the default shader from `bevy_eidolon::cull::functions`. The material
+    /// bindings are available at group 2, as long as their visibility includes compute.
+    ///
+    /// Entities of a material with a custom cull shader are always culled with their own
+    /// dispatch, even in `GpuCullMode::Arena`.
+    fn cull_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Allow specializing the cull pipeline of a custom [`InstancedMaterial::cull_shader`].
+    fn specialize_cull(_descriptor: &mut ComputePipelineDescriptor, _key: Self::Data) {}
 }
 
 #[derive(Asset, TypePath, AsBindGroup, Debug, Clone, Default)]
@@ -69,6 +120,8 @@ pub struct StandardInstancedMaterial {
     pub debug_color: Color,
     pub polygon_mode: PolygonMode,
     pub double_sided: bool,
+    pub disable_shadows: bool,
+    pub alpha_mode: InstancedAlphaMode,
 }
 
 impl From<&StandardInstancedMaterial> for InstancedMaterialKey {
@@ -116,6 +169,14 @@ impl InstancedMaterial for StandardInstancedMaterial {
         self.gpu_cull
     }
 
+    fn casts_shadows(&self) -> bool {
+        !self.disable_shadows
+    }
+
+    fn alpha_mode(&self) -> InstancedAlphaMode {
+        self.alpha_mode
+    }
+
     fn specialize(
         descriptor: &mut RenderPipelineDescriptor,
         _layout: &MeshVertexBufferLayoutRef,
@@ -125,10 +186,6 @@ impl InstancedMaterial for StandardInstancedMaterial {
             descriptor.primitive.cull_mode = None;
         }
 
-        if key.contains(InstancedMaterialKey::GPU_CULL) {
-            // TODO
-        }
-
         if key.contains(InstancedMaterialKey::POINTS) {
             descriptor.primitive.polygon_mode = PolygonMode::Point;
         }
@@ -151,6 +208,40 @@ impl InstancedMaterial for StandardInstancedMaterial {
     }
 }
 
+/// How the alpha of an instanced material is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InstancedAlphaMode {
+    #[default]
+    Opaque,
+    /// Discards fragments with an alpha below the cutoff.
+    ///
+    /// The cutoff is available to shaders as `instance_uniforms.alpha_cutoff`, the discarding
+    /// code paths are guarded by the `MAY_DISCARD` shader def.
+    Mask(f32),
+    /// Blends with alpha, entities are sorted back to front and skip the prepass.
+    ///
+    /// Instances of an entity are drawn in cull order, see
+    /// [`GpuInstanceSort`](crate::components::GpuInstanceSort) to sort them as well.
+    Blend,
+}
+
+impl InstancedAlphaMode {
+    pub fn pipeline_key(self) -> MeshPipelineKey {
+        match self {
+            InstancedAlphaMode::Opaque => MeshPipelineKey::NONE,
+            InstancedAlphaMode::Mask(_) => MeshPipelineKey::MAY_DISCARD,
+            InstancedAlphaMode::Blend => MeshPipelineKey::BLEND_ALPHA,
+        }
+    }
+
+    pub fn cutoff(self) -> f32 {
+        match self {
+            InstancedAlphaMode::Opaque | InstancedAlphaMode::Blend => 0.0,
+            InstancedAlphaMode::Mask(cutoff) => cutoff,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(ShaderType, Clone, Zeroable, Copy, Pod)]
 pub struct InstancedMaterialUniforms {
@@ -169,14 +260,21 @@ pub struct InstancedMeshMaterial<M>(pub Handle<M>)
 where
     M: InstancedMaterial;
 
-impl<M: InstancedMaterial> ExtractComponent for InstancedMeshMaterial<M> {
-    type QueryData = &'static InstancedMeshMaterial<M>;
-    type QueryFilter = ();
-    type Out = Self;
-
-    fn extract_component(item: QueryItem<'_, '_, Self::QueryData>) -> Option<Self> {
-        Some(item.clone())
-    }
+/// Extracts changed [`InstancedMeshMaterial`]s, and those of render entities that were respawned
+/// empty after a synced component of their main entity was removed.
+pub fn extract_instanced_mesh_materials<M: InstancedMaterial>(
+    mut commands: Commands,
+    query: Extract<Query<(RenderEntity, Ref<InstancedMeshMaterial<M>>)>>,
+    extracted: Query<(), With<InstancedMeshMaterial<M>>>,
+) {
+    let values: Vec<_> = query
+        .iter()
+        .filter(|(render_entity, material)| {
+            material.is_changed() || !extracted.contains(*render_entity)
+        })
+        .map(|(render_entity, material)| (render_entity, material.clone()))
+        .collect();
+    commands.try_insert_batch(values);
 }
 
 impl<'a> From<&'a StandardInstancedMaterial> for InstancedMaterialUniforms {
@@ -1,6 +1,25 @@
 #define_import_path bevy_eidolon::render::bindings
 
-#import bevy_eidolon::render::types::{MaterialUniforms, InstanceUniforms}
+#import bevy_eidolon::render::types::{MaterialUniforms, InstanceUniforms, PreviousInstance}
+#ifdef BATCHED_INSTANCES
+#import bevy_eidolon::render::types::BatchedInstanceUniforms
+#endif
 
 @group(3) @binding(0) var<uniform> material: MaterialUniforms;
+#ifdef BATCHED_INSTANCES
+@group(3) @binding(100) var<storage, read> batched_instance_uniforms: array<BatchedInstanceUniforms>;
+// Set by `load_instance_uniforms`.
+var<private> instance_uniforms: InstanceUniforms;
+
+// Selects the uniforms of the instance's entity in batched draws, has to be called first in
+// every entry point with `Vertex::i_uniform_index` or `VertexOutput::uniform_index`.
+fn load_instance_uniforms(index: u32) {
+    instance_uniforms = batched_instance_uniforms[index].uniforms;
+}
+#else
 @group(3) @binding(100) var<uniform> instance_uniforms: InstanceUniforms;
+#endif
+
+#ifdef PREVIOUS_INSTANCE_DATA
+@group(2) @binding(0) var<storage, read> previous_instances: array<PreviousInstance>;
+#endif
@@ -1,12 +1,18 @@
 use crate::prelude::*;
-use bevy_ecs::system::{SystemParamItem, lifetimeless::*};
+use bevy_core_pipeline::prepass::MotionVectorPrepass;
+use bevy_ecs::{
+    query::Has,
+    system::{SystemParamItem, lifetimeless::*},
+};
 use bevy_pbr::{
-    RenderMeshInstances, SetMeshBindGroup, SetMeshViewBindGroup, SetMeshViewBindingArrayBindGroup,
+    PrepassViewBindGroup, RenderMeshInstances, SetMeshBindGroup, SetMeshViewBindGroup,
+    SetMeshViewBindingArrayBindGroup, SetPrepassViewBindGroup, SetPrepassViewEmptyBindGroup,
 };
 use bevy_render::{
     mesh::{RenderMesh, RenderMeshBufferInfo, allocator::MeshAllocator},
     render_asset::RenderAssets,
     render_phase::*,
+    render_resource::{DrawIndexedIndirectArgs, DrawIndirectArgs},
 };
 use std::marker::PhantomData;
 
@@ -19,26 +25,99 @@ pub type DrawInstancedMaterial<M> = (
     DrawInstancedMaterialMesh<M>,
 );
 
+/// Draws the visible instances into the camera prepasses, see
+/// [`InstancedMaterialPrepassPipeline`](crate::render::pipeline::InstancedMaterialPrepassPipeline).
+pub type DrawInstancedPrepass<M> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetPrepassViewEmptyBindGroup<1>,
+    SetInstancedPreviousBindGroup<2>,
+    SetInstancedCombinedBindGroup<3>,
+    DrawInstancedMaterialMesh<M>,
+);
+
+/// Draws all instances into the shadow views.
+pub type DrawInstancedShadow<M> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetPrepassViewEmptyBindGroup<1>,
+    SetPrepassViewEmptyBindGroup<2>,
+    SetInstancedCombinedBindGroup<3>,
+    DrawInstancedShadowMesh<M>,
+);
+
+/// Binds the uniforms of the entity, or of all entities for batches of the
+/// [`InstanceCullArena`].
 pub struct SetInstancedCombinedBindGroup<const I: usize>;
 
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetInstancedCombinedBindGroup<I> {
-    type Param = ();
+    type Param = Option<SRes<InstanceCullArena>>;
     type ViewQuery = ();
-    type ItemQuery = Read<InstancedCombinedBindGroup>;
+    type ItemQuery = (
+        Read<InstancedCombinedBindGroup>,
+        Read<PreparedInstanceUniforms>,
+    );
 
     #[inline]
     fn render<'w>(
-        _item: &P,
+        item: &P,
         _view: (),
-        item: Option<&'w InstancedCombinedBindGroup>,
-        _param: SystemParamItem<'w, '_, Self::Param>,
+        item_query: Option<(&'w InstancedCombinedBindGroup, &'w PreparedInstanceUniforms)>,
+        arena: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let Some(combined_bind_group) = item else {
+        let Some((combined_bind_group, uniforms)) = item_query else {
             return RenderCommandResult::Skip;
         };
 
-        pass.set_bind_group(I, &combined_bind_group.0, &[]);
+        // Has to match `InstancedMaterialPipelineKey::batched`.
+        if arena.is_some_and(|arena| arena.into_inner().chunks.contains_key(&item.entity())) {
+            let Some(batched_bind_group) = &combined_bind_group.batched_bind_group else {
+                return RenderCommandResult::Skip;
+            };
+            pass.set_bind_group(I, batched_bind_group, &[]);
+        } else {
+            pass.set_bind_group(I, &combined_bind_group.bind_group, &[uniforms.offset]);
+        }
+
+        RenderCommandResult::Success
+    }
+}
+
+/// Binds the [`PreviousInstanceBuffer`] in views with a motion vector prepass, the empty bind
+/// group otherwise.
+pub struct SetInstancedPreviousBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetInstancedPreviousBindGroup<I> {
+    type Param = SRes<PrepassViewBindGroup>;
+    type ViewQuery = Has<MotionVectorPrepass>;
+    type ItemQuery = (
+        Has<InstancePreviousData>,
+        Option<Read<PreviousInstanceBuffer>>,
+    );
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        motion_vector_prepass: bool,
+        item: Option<(bool, Option<&'w PreviousInstanceBuffer>)>,
+        prepass_view_bind_group: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some((previous_data, previous_buffer)) = item else {
+            return RenderCommandResult::Skip;
+        };
+
+        // Has to match `InstancedMaterialPrepassPipelineKey::previous_instances`.
+        if motion_vector_prepass && previous_data {
+            let Some(previous_buffer) = previous_buffer else {
+                return RenderCommandResult::Skip;
+            };
+            pass.set_bind_group(I, &previous_buffer.bind_group, &[]);
+        } else {
+            let prepass_view_bind_group = prepass_view_bind_group.into_inner();
+            pass.set_bind_group(I, &prepass_view_bind_group.empty_bind_group, &[]);
+        }
 
         RenderCommandResult::Success
     }
@@ -55,21 +134,237 @@ where
         SRes<RenderAssets<RenderMesh>>,
         SRes<RenderMeshInstances>,
         SRes<MeshAllocator>,
+        Option<SRes<InstanceCullArena>>,
+    );
+
+    type ViewQuery = Option<Read<ViewCullBuffer>>;
+
+    type ItemQuery = (
+        Option<Read<InstanceBuffer>>,
+        Option<Read<GpuDrawIndexedIndirect>>,
+        Option<Read<GpuDrawIndirect>>,
+        Option<Read<InstanceLodMeshes>>,
+    );
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view_cull_buffer: Option<&'w ViewCullBuffer>,
+        items: Option<(
+            Option<&'w InstanceBuffer>,
+            Option<&'w GpuDrawIndexedIndirect>,
+            Option<&'w GpuDrawIndirect>,
+            Option<&'w InstanceLodMeshes>,
+        )>,
+        (meshes, render_mesh_instances, mesh_allocator, arena): SystemParamItem<
+            'w,
+            '_,
+            Self::Param,
+        >,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some((instance_buffer, indexed_indirect_draw_opt, indirect_draw_opt, lod_meshes)) =
+            items
+        else {
+            return RenderCommandResult::Skip;
+        };
+
+        let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(item.main_entity())
+        else {
+            return RenderCommandResult::Skip;
+        };
+
+        let mesh_allocator = mesh_allocator.into_inner();
+
+        if let Some(arena) = arena.map(|arena| arena.into_inner())
+            && let Some(chunk) = arena.chunks.get(&item.entity())
+        {
+            let (Some(buffers), Some(view_cull_buffer)) = (&arena.buffers, view_cull_buffer) else {
+                return RenderCommandResult::Skip;
+            };
+            let Some(gpu_mesh) = meshes.into_inner().get(mesh_instance.mesh_asset_id) else {
+                return RenderCommandResult::Skip;
+            };
+            let Some(vertex_buffer_slice) =
+                mesh_allocator.mesh_vertex_slice(&mesh_instance.mesh_asset_id)
+            else {
+                return RenderCommandResult::Skip;
+            };
+
+            // The draws of all LOD levels and all entities of the batch select their instances
+            // with `first_instance`.
+            pass.set_vertex_buffer(0, vertex_buffer_slice.buffer.slice(..));
+            pass.set_vertex_buffer(1, buffers.output.slice(..));
+
+            let view_index = view_cull_buffer.view_index;
+
+            match &gpu_mesh.buffer_info {
+                RenderMeshBufferInfo::Indexed { index_format, .. } if chunk.indexed => {
+                    let Some(index_buffer_slice) =
+                        mesh_allocator.mesh_index_slice(&mesh_instance.mesh_asset_id)
+                    else {
+                        return RenderCommandResult::Skip;
+                    };
+
+                    pass.set_index_buffer(index_buffer_slice.buffer.slice(..), 0, *index_format);
+
+                    let first_draw =
+                        view_index * buffers.indexed_view_draw_stride + chunk.first_draw;
+                    let offset = first_draw as u64 * size_of::<DrawIndexedIndirectArgs>() as u64;
+                    if arena.multi_draw_indirect {
+                        pass.multi_draw_indexed_indirect(
+                            &buffers.indexed_indirect,
+                            offset,
+                            chunk.batch_draw_count,
+                        );
+                    } else {
+                        for draw in 0..chunk.batch_draw_count as u64 {
+                            pass.draw_indexed_indirect(
+                                &buffers.indexed_indirect,
+                                offset + draw * size_of::<DrawIndexedIndirectArgs>() as u64,
+                            );
+                        }
+                    }
+                }
+                RenderMeshBufferInfo::NonIndexed if !chunk.indexed => {
+                    let first_draw =
+                        view_index * buffers.non_indexed_view_draw_stride + chunk.first_draw;
+                    let offset = first_draw as u64 * size_of::<DrawIndirectArgs>() as u64;
+                    if arena.multi_draw_indirect {
+                        pass.multi_draw_indirect(&buffers.indirect, offset, chunk.batch_draw_count);
+                    } else {
+                        for draw in 0..chunk.batch_draw_count as u64 {
+                            pass.draw_indirect(
+                                &buffers.indirect,
+                                offset + draw * size_of::<DrawIndirectArgs>() as u64,
+                            );
+                        }
+                    }
+                }
+                _ => return RenderCommandResult::Skip,
+            }
+
+            return RenderCommandResult::Success;
+        }
+
+        let Some(instance_buffer) = instance_buffer else {
+            return RenderCommandResult::Skip;
+        };
+
+        let meshes = meshes.into_inner();
+
+        // GPU culled entities have separate instances and draw arguments per view and LOD.
+        let view_index = match view_cull_buffer {
+            Some(view_cull_buffer) => view_cull_buffer.view_index as u64,
+            None if instance_buffer.view_stride != 0 => return RenderCommandResult::Skip,
+            None => 0,
+        };
+
+        let lod_mesh_ids = lod_meshes.map_or(
+            std::slice::from_ref(&mesh_instance.mesh_asset_id),
+            |lod_meshes| lod_meshes.0.as_slice(),
+        );
+
+        for (level, mesh_asset_id) in lod_mesh_ids.iter().enumerate() {
+            let level = level as u64;
+
+            let Some(gpu_mesh) = meshes.get(*mesh_asset_id) else {
+                continue;
+            };
+            let Some(vertex_buffer_slice) = mesh_allocator.mesh_vertex_slice(mesh_asset_id) else {
+                continue;
+            };
+
+            pass.set_vertex_buffer(0, vertex_buffer_slice.buffer.slice(..));
+            pass.set_vertex_buffer(
+                1,
+                instance_buffer.buffer.slice(
+                    instance_buffer.view_stride * view_index + instance_buffer.lod_stride * level..,
+                ),
+            );
+
+            match &gpu_mesh.buffer_info {
+                RenderMeshBufferInfo::Indexed {
+                    index_format,
+                    count: _,
+                } => {
+                    let Some(indirect_draw) = indexed_indirect_draw_opt else {
+                        return RenderCommandResult::Skip;
+                    };
+
+                    let Some(index_buffer_slice) = mesh_allocator.mesh_index_slice(mesh_asset_id)
+                    else {
+                        continue;
+                    };
+
+                    pass.set_index_buffer(index_buffer_slice.buffer.slice(..), 0, *index_format);
+
+                    pass.draw_indexed_indirect(
+                        &indirect_draw.buffer,
+                        indirect_draw.offset
+                            + indirect_draw.view_stride * view_index
+                            + indirect_draw.lod_stride * level,
+                    );
+                }
+                RenderMeshBufferInfo::NonIndexed => {
+                    if let Some(indirect_draw) = indirect_draw_opt {
+                        pass.draw_indirect(
+                            &indirect_draw.buffer,
+                            indirect_draw.offset
+                                + indirect_draw.view_stride * view_index
+                                + indirect_draw.lod_stride * level,
+                        );
+                    } else {
+                        pass.draw(vertex_buffer_slice.range, 0..instance_buffer.length as u32);
+                    }
+                }
+            }
+        }
+        RenderCommandResult::Success
+    }
+}
+
+pub struct DrawInstancedShadowMesh<M: InstancedMaterial>(PhantomData<M>);
+
+impl<P, M> RenderCommand<P> for DrawInstancedShadowMesh<M>
+where
+    P: PhaseItem,
+    M: InstancedMaterial,
+{
+    type Param = (
+        SRes<RenderAssets<RenderMesh>>,
+        SRes<RenderMeshInstances>,
+        SRes<MeshAllocator>,
+        Option<SRes<InstanceCullArena>>,
     );
 
     type ViewQuery = ();
 
-    type ItemQuery = (Read<InstanceBuffer>, Option<Read<GpuDrawIndexedIndirect>>);
+    type ItemQuery = (
+        Option<Read<InstanceBuffer>>,
+        Option<Read<InstancedComputeSourceBuffer>>,
+        Option<Read<InstanceShadowSettings>>,
+        Option<Read<InstanceLods>>,
+    );
 
     #[inline]
     fn render<'w>(
         item: &P,
         _view: (),
-        items: Option<(&'w InstanceBuffer, Option<&'w GpuDrawIndexedIndirect>)>,
-        (meshes, render_mesh_instances, mesh_allocator): SystemParamItem<'w, '_, Self::Param>,
+        items: Option<(
+            Option<&'w InstanceBuffer>,
+            Option<&'w InstancedComputeSourceBuffer>,
+            Option<&'w InstanceShadowSettings>,
+            Option<&'w InstanceLods>,
+        )>,
+        (meshes, render_mesh_instances, mesh_allocator, arena): SystemParamItem<
+            'w,
+            '_,
+            Self::Param,
+        >,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let Some((instance_buffer, indirect_draw_opt)) = items else {
+        let Some((instance_buffer, source_buffer, shadow_settings, lods)) = items else {
             return RenderCommandResult::Skip;
         };
 
@@ -77,43 +372,68 @@ where
         else {
             return RenderCommandResult::Skip;
         };
-        let Some(gpu_mesh) = meshes.into_inner().get(mesh_instance.mesh_asset_id) else {
+
+        let mesh_asset_id = shadow_settings.map_or(mesh_instance.mesh_asset_id, |settings| {
+            settings.mesh(mesh_instance.mesh_asset_id, lods)
+        });
+
+        // The cull pass only keeps the instances visible to the cameras, shadows are drawn from
+        // the source instances instead.
+        let arena_instances = arena.and_then(|arena| {
+            let arena = arena.into_inner();
+            let chunk = arena.chunks.get(&item.entity())?;
+            let buffers = arena.buffers.as_ref()?;
+            Some((&buffers.source, chunk.first_instance, chunk.instance_count))
+        });
+
+        let Some((buffer, first_instance, instance_count)) = arena_instances
+            .or(source_buffer.map(|source| (&source.buffer, 0, source.count)))
+            .or(instance_buffer.map(|instances| (&instances.buffer, 0, instances.length as u32)))
+        else {
             return RenderCommandResult::Skip;
         };
 
+        if instance_count == 0 {
+            return RenderCommandResult::Skip;
+        }
+
         let mesh_allocator = mesh_allocator.into_inner();
-        let Some(vertex_buffer_slice) =
-            mesh_allocator.mesh_vertex_slice(&mesh_instance.mesh_asset_id)
-        else {
+
+        let Some(gpu_mesh) = meshes.into_inner().get(mesh_asset_id) else {
+            return RenderCommandResult::Skip;
+        };
+        let Some(vertex_buffer_slice) = mesh_allocator.mesh_vertex_slice(&mesh_asset_id) else {
             return RenderCommandResult::Skip;
         };
 
         pass.set_vertex_buffer(0, vertex_buffer_slice.buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+        pass.set_vertex_buffer(
+            1,
+            buffer.slice(first_instance as u64 * size_of::<InstanceData>() as u64..),
+        );
 
         match &gpu_mesh.buffer_info {
             RenderMeshBufferInfo::Indexed {
                 index_format,
-                count: _,
+                count,
             } => {
-                let Some(indirect_draw) = indirect_draw_opt else {
-                    return RenderCommandResult::Skip;
-                };
-
-                let Some(index_buffer_slice) =
-                    mesh_allocator.mesh_index_slice(&mesh_instance.mesh_asset_id)
+                let Some(index_buffer_slice) = mesh_allocator.mesh_index_slice(&mesh_asset_id)
                 else {
                     return RenderCommandResult::Skip;
                 };
 
                 pass.set_index_buffer(index_buffer_slice.buffer.slice(..), 0, *index_format);
-
-                pass.draw_indexed_indirect(&indirect_draw.buffer, indirect_draw.offset);
+                pass.draw_indexed(
+                    index_buffer_slice.range.start..index_buffer_slice.range.start + count,
+                    vertex_buffer_slice.range.start as i32,
+                    0..instance_count,
+                );
             }
             RenderMeshBufferInfo::NonIndexed => {
-                pass.draw(vertex_buffer_slice.range, 0..instance_buffer.length as u32);
+                pass.draw(vertex_buffer_slice.range, 0..instance_count);
             }
         }
+
         RenderCommandResult::Success
     }
 }
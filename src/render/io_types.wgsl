@@ -25,9 +25,20 @@ struct Vertex {
     @location(7) joint_weights: vec4<f32>,
 #endif
 
+#ifdef ORIENTED_INSTANCES
+    @location(8) i_position: vec3<f32>,
+    // Quaternion.
+    @location(9) i_rotation: vec4<f32>,
+    @location(12) i_scale: vec3<f32>,
+#else
     @location(8) i_pos_scale: vec4<f32>,
     @location(9) i_rotation: f32,
+#endif
     @location(10) i_index: u32,
+    @location(11) i_color: vec4<f32>,
+#ifdef BATCHED_INSTANCES
+    @location(14) i_uniform_index: u32,
+#endif
 };
 
 struct VertexOutput {
@@ -42,4 +53,49 @@ struct VertexOutput {
     @location(3) uv: vec2<f32>,
     @location(4) world_tangent: vec4<f32>,
     @location(5) local_pos: vec3<f32>,
+    @location(6) color: vec4<f32>,
+#ifdef BATCHED_INSTANCES
+    @location(7) @interpolate(flat) uniform_index: u32,
+#endif
 };
+
+struct PrepassVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+
+#ifdef VISIBILITY_RANGE_DITHER
+    @location(0) @interpolate(flat) visibility_range_dither: i32,
+#endif
+
+#ifdef NORMAL_PREPASS
+    @location(1) world_normal: vec3<f32>,
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    @location(2) world_position: vec4<f32>,
+    @location(4) previous_world_position: vec4<f32>,
+#endif
+
+#ifdef VERTEX_UVS_A
+    @location(3) uv: vec2<f32>,
+#endif
+
+#ifdef MAY_DISCARD
+    @location(5) color: vec4<f32>,
+#endif
+
+#ifdef BATCHED_INSTANCES
+    @location(6) @interpolate(flat) uniform_index: u32,
+#endif
+};
+
+#ifdef PREPASS_FRAGMENT
+struct PrepassFragmentOutput {
+#ifdef NORMAL_PREPASS
+    @location(0) normal: vec4<f32>,
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    @location(1) motion_vector: vec2<f32>,
+#endif
+};
+#endif
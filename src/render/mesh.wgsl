@@ -1,6 +1,9 @@
 #import bevy_pbr::mesh_view_bindings::view
 
 #import bevy_eidolon::render::bindings::{material, instance_uniforms}
+#ifdef BATCHED_INSTANCES
+#import bevy_eidolon::render::bindings::load_instance_uniforms
+#endif
 #import bevy_eidolon::render::utils
 #import bevy_eidolon::render::io_types::{VertexOutput, Vertex}
 
@@ -9,10 +12,21 @@
 fn vertex(vertex: Vertex) -> VertexOutput {
     var out: VertexOutput;
 
-    var scale = vertex.i_pos_scale.w;
-    var translation = vertex.i_pos_scale.xyz;
+#ifdef BATCHED_INSTANCES
+    load_instance_uniforms(vertex.i_uniform_index);
+    out.uniform_index = vertex.i_uniform_index;
+#endif
 
+#ifdef ORIENTED_INSTANCES
+    let final_matrix = utils::calculate_oriented_instance_world_matrix(
+        vertex.i_position,
+        vertex.i_rotation,
+        vertex.i_scale,
+        instance_uniforms.world_from_local
+    );
+#else
     let final_matrix = utils::calculate_instance_world_matrix(vertex.i_pos_scale, vertex.i_rotation, instance_uniforms.world_from_local);
+#endif
 
     let world_position = final_matrix * vec4<f32>(vertex.position, 1.0);
 
@@ -40,6 +54,8 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     out.uv = vec2<f32>(0.0);
 #endif
 
+    out.color = vertex.i_color;
+
 #ifdef VISIBILITY_RANGE_DITHER
     out.visibility_range_dither = utils::get_visibility_range_dither_level(
         instance_uniforms.visibility_range,
@@ -1,13 +1,15 @@
 use std::fmt;
-use std::mem::size_of;
+use std::mem::{offset_of, size_of};
 use std::num::NonZeroU64;
 
 use bevy_asset::*;
+use bevy_core_pipeline::{core_3d::CORE_3D_DEPTH_FORMAT, prepass::prepass_target_descriptors};
 use bevy_ecs::prelude::*;
-use bevy_mesh::{MeshVertexBufferLayoutRef, VertexBufferLayout};
-use bevy_pbr::{MeshPipeline, MeshPipelineKey};
+use bevy_mesh::{Mesh, MeshVertexBufferLayoutRef, VertexBufferLayout};
+use bevy_pbr::{MeshPipeline, MeshPipelineKey, PrepassPipeline};
 use bevy_render::{render_resource::*, renderer::RenderDevice};
-use bevy_shader::{Shader, ShaderRef};
+use bevy_shader::{Shader, ShaderDefVal, ShaderRef};
+use bevy_utils::default;
 
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
@@ -17,6 +19,10 @@ use crate::render::prepare::INSTANCE_BINDING_INDEX;
 
 pub struct InstancedMaterialPipelineKey<M: InstancedMaterial> {
     pub mesh_key: MeshPipelineKey,
+    pub instance_layout: InstanceLayout,
+    /// Drawn in batches of the [`InstanceCullArena`], see
+    /// [`InstancedMaterialPipeline::batched_combined_layout`].
+    pub batched: bool,
     pub bind_group_data: M::Data,
 }
 
@@ -28,6 +34,8 @@ where
     fn clone(&self) -> Self {
         Self {
             mesh_key: self.mesh_key,
+            instance_layout: self.instance_layout,
+            batched: self.batched,
             bind_group_data: self.bind_group_data.clone(),
         }
     }
@@ -39,7 +47,10 @@ where
     M::Data: PartialEq,
 {
     fn eq(&self, other: &Self) -> bool {
-        self.mesh_key == other.mesh_key && self.bind_group_data == other.bind_group_data
+        self.mesh_key == other.mesh_key
+            && self.instance_layout == other.instance_layout
+            && self.batched == other.batched
+            && self.bind_group_data == other.bind_group_data
     }
 }
 
@@ -57,6 +68,8 @@ where
 {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.mesh_key.hash(state);
+        self.instance_layout.hash(state);
+        self.batched.hash(state);
         self.bind_group_data.hash(state);
     }
 }
@@ -69,6 +82,8 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("InstancedMaterialPipelineKey")
             .field("mesh_key", &self.mesh_key)
+            .field("instance_layout", &self.instance_layout)
+            .field("batched", &self.batched)
             .field("bind_group_data", &self.bind_group_data)
             .finish()
     }
@@ -88,6 +103,13 @@ pub struct InstancedMaterialPipeline<M: InstancedMaterial> {
     /// Used in the render pipeline.
     pub combined_layout: BindGroupLayout,
 
+    /// Replaces `combined_layout` in batched draws, binds the uniforms of all entities as a
+    /// storage buffer. `None` if the device doesn't support storage buffers in vertex shaders.
+    pub batched_combined_layout: Option<BindGroupLayout>,
+
+    /// See [`InstanceUniformsBuffer::stride`].
+    pub instance_uniforms_stride: u64,
+
     pub _phantom: PhantomData<M>,
 }
 
@@ -115,12 +137,15 @@ impl<M: InstancedMaterial> FromWorld for InstancedMaterialPipeline<M> {
             );
         }
 
+        let mut batched_entries = combined_entries.clone();
+
         combined_entries.push(BindGroupLayoutEntry {
             binding: INSTANCE_BINDING_INDEX,
             visibility: ShaderStages::VERTEX_FRAGMENT,
             ty: BindingType::Buffer {
                 ty: BufferBindingType::Uniform,
-                has_dynamic_offset: false,
+                // See `InstanceUniformsBuffer`.
+                has_dynamic_offset: true,
                 min_binding_size: NonZeroU64::new(size_of::<InstanceUniforms>() as u64),
             },
             count: None,
@@ -135,6 +160,28 @@ impl<M: InstancedMaterial> FromWorld for InstancedMaterialPipeline<M> {
             &combined_entries,
         );
 
+        let batched_combined_layout = batched_instances_supported(render_device).then(|| {
+            batched_entries.push(BindGroupLayoutEntry {
+                binding: INSTANCE_BINDING_INDEX,
+                visibility: ShaderStages::VERTEX_FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            });
+
+            render_device.create_bind_group_layout(
+                format!(
+                    "instanced_material_batched_combined_layout_{}",
+                    std::any::type_name::<M>()
+                )
+                .as_str(),
+                &batched_entries,
+            )
+        });
+
         let vertex_shader = resolve_shader(asset_server, M::vertex_shader(), "mesh.wgsl");
         let fragment_shader = resolve_shader(asset_server, M::fragment_shader(), "shading.wgsl");
 
@@ -144,6 +191,8 @@ impl<M: InstancedMaterial> FromWorld for InstancedMaterialPipeline<M> {
             mesh_pipeline,
             material_layout,
             combined_layout,
+            batched_combined_layout,
+            instance_uniforms_stride: instance_uniforms_stride(render_device),
             _phantom: PhantomData,
         }
     }
@@ -163,25 +212,45 @@ where
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
         let mut descriptor = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
 
-        descriptor.layout.push(self.combined_layout.clone());
+        descriptor.layout.push(if key.batched {
+            self.batched_combined_layout
+                .clone()
+                .expect("batched draws require storage buffers in vertex shaders")
+        } else {
+            self.combined_layout.clone()
+        });
+
+        let blend = key
+            .mesh_key
+            .intersection(MeshPipelineKey::BLEND_RESERVED_BITS)
+            == MeshPipelineKey::BLEND_ALPHA;
 
         if let Some(ds) = descriptor.depth_stencil.as_mut() {
-            ds.depth_write_enabled = true;
+            ds.depth_write_enabled = !blend;
             ds.depth_compare = CompareFunction::GreaterEqual;
         }
 
         let shader_defs = &mut descriptor.vertex.shader_defs;
 
         shader_defs.push("VISIBILITY_RANGE_DITHER".into());
+        shader_defs.extend(key.instance_layout.shader_defs());
+        if key.batched {
+            shader_defs.extend(batched_shader_defs(self.instance_uniforms_stride));
+        }
 
         if let Some(fragment) = descriptor.fragment.as_mut() {
             if let Some(target) = fragment.targets.get_mut(0)
                 && let Some(target) = target
             {
-                target.blend = None;
+                target.blend = blend.then_some(BlendState::ALPHA_BLENDING);
             }
 
             fragment.shader_defs.push("VISIBILITY_RANGE_DITHER".into());
+            if key.batched {
+                fragment
+                    .shader_defs
+                    .extend(batched_shader_defs(self.instance_uniforms_stride));
+            }
         }
 
         M::specialize(&mut descriptor, layout, key.bind_group_data)?;
@@ -189,35 +258,398 @@ where
         descriptor.vertex.shader = self.vertex_shader.clone();
         descriptor.fragment.as_mut().unwrap().shader = self.fragment_shader.clone();
 
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: size_of::<InstanceData>() as u64,
-            step_mode: VertexStepMode::Instance,
-            attributes: vec![
-                // Position + Scale
-                VertexAttribute {
-                    format: VertexFormat::Float32x4,
-                    offset: 0,
-                    shader_location: 8,
-                },
-                // Rotation
-                VertexAttribute {
-                    format: VertexFormat::Float32,
-                    offset: VertexFormat::Float32x4.size(),
-                    shader_location: 9,
+        descriptor
+            .vertex
+            .buffers
+            .push(instance_vertex_buffer_layout(
+                key.instance_layout,
+                key.batched,
+            ));
+
+        Ok(descriptor)
+    }
+}
+
+pub struct InstancedMaterialPrepassPipelineKey<M: InstancedMaterial> {
+    pub mesh_key: MeshPipelineKey,
+    /// Drawing into a shadow view instead of a camera prepass.
+    pub shadow_pass: bool,
+    /// Binds the instances of the previous frame in group 2, see [`InstancePreviousData`].
+    pub previous_instances: bool,
+    pub instance_layout: InstanceLayout,
+    /// See [`InstancedMaterialPipelineKey::batched`].
+    pub batched: bool,
+    pub bind_group_data: M::Data,
+}
+
+impl<M> Clone for InstancedMaterialPrepassPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            mesh_key: self.mesh_key,
+            shadow_pass: self.shadow_pass,
+            previous_instances: self.previous_instances,
+            instance_layout: self.instance_layout,
+            batched: self.batched,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
+
+impl<M> PartialEq for InstancedMaterialPrepassPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.mesh_key == other.mesh_key
+            && self.shadow_pass == other.shadow_pass
+            && self.previous_instances == other.previous_instances
+            && self.instance_layout == other.instance_layout
+            && self.batched == other.batched
+            && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M> Eq for InstancedMaterialPrepassPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: Eq,
+{
+}
+
+impl<M> Hash for InstancedMaterialPrepassPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: Hash,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mesh_key.hash(state);
+        self.shadow_pass.hash(state);
+        self.previous_instances.hash(state);
+        self.instance_layout.hash(state);
+        self.batched.hash(state);
+        self.bind_group_data.hash(state);
+    }
+}
+
+impl<M> fmt::Debug for InstancedMaterialPrepassPipelineKey<M>
+where
+    M: InstancedMaterial,
+    M::Data: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstancedMaterialPrepassPipelineKey")
+            .field("mesh_key", &self.mesh_key)
+            .field("shadow_pass", &self.shadow_pass)
+            .field("previous_instances", &self.previous_instances)
+            .field("instance_layout", &self.instance_layout)
+            .field("batched", &self.batched)
+            .field("bind_group_data", &self.bind_group_data)
+            .finish()
+    }
+}
+
+/// Pipeline of instanced materials in the camera prepasses and shadow views.
+///
+/// Uses the view layouts of the prepass, so it is created in `RenderStartup` after
+/// [`init_prepass_pipeline`](bevy_pbr::init_prepass_pipeline).
+#[derive(Resource)]
+pub struct InstancedMaterialPrepassPipeline<M: InstancedMaterial> {
+    pub vertex_shader: Handle<Shader>,
+    pub fragment_shader: Handle<Shader>,
+    pub view_layout_motion_vectors: BindGroupLayout,
+    pub view_layout_no_motion_vectors: BindGroupLayout,
+    pub empty_layout: BindGroupLayout,
+    /// Instances of the previous frame, see [`PreviousInstanceBuffer`].
+    pub previous_instances_layout: BindGroupLayout,
+    /// See [`InstancedMaterialPipeline::combined_layout`].
+    pub combined_layout: BindGroupLayout,
+    /// See [`InstancedMaterialPipeline::batched_combined_layout`].
+    pub batched_combined_layout: Option<BindGroupLayout>,
+    /// See [`InstanceUniformsBuffer::stride`].
+    pub instance_uniforms_stride: u64,
+    pub depth_clip_control_supported: bool,
+    pub _phantom: PhantomData<M>,
+}
+
+pub(crate) fn init_instanced_material_prepass_pipeline<M: InstancedMaterial>(
+    mut commands: Commands,
+    prepass_pipeline: Res<PrepassPipeline>,
+    material_pipeline: Res<InstancedMaterialPipeline<M>>,
+    asset_server: Res<AssetServer>,
+    render_device: Res<RenderDevice>,
+) {
+    let previous_instances_layout = render_device.create_bind_group_layout(
+        "instanced_material_previous_instances_layout",
+        &[BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                // Depends on the `InstanceLayout`, validated against the shader when binding.
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+    );
+
+    commands.insert_resource(InstancedMaterialPrepassPipeline::<M> {
+        vertex_shader: resolve_shader(&asset_server, M::prepass_vertex_shader(), "prepass.wgsl"),
+        fragment_shader: resolve_shader(
+            &asset_server,
+            M::prepass_fragment_shader(),
+            "prepass.wgsl",
+        ),
+        view_layout_motion_vectors: prepass_pipeline.view_layout_motion_vectors.clone(),
+        view_layout_no_motion_vectors: prepass_pipeline.view_layout_no_motion_vectors.clone(),
+        empty_layout: prepass_pipeline.empty_layout.clone(),
+        previous_instances_layout,
+        combined_layout: material_pipeline.combined_layout.clone(),
+        batched_combined_layout: material_pipeline.batched_combined_layout.clone(),
+        instance_uniforms_stride: material_pipeline.instance_uniforms_stride,
+        depth_clip_control_supported: prepass_pipeline.depth_clip_control_supported,
+        _phantom: PhantomData,
+    });
+}
+
+impl<M> SpecializedMeshPipeline for InstancedMaterialPrepassPipeline<M>
+where
+    M: InstancedMaterial,
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = InstancedMaterialPrepassPipelineKey<M>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayoutRef,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mesh_key = key.mesh_key;
+        let normal_prepass = mesh_key.contains(MeshPipelineKey::NORMAL_PREPASS);
+        let motion_vector_prepass = mesh_key.contains(MeshPipelineKey::MOTION_VECTOR_PREPASS);
+
+        let mut shader_defs = Vec::<ShaderDefVal>::new();
+        let mut vertex_attributes = vec![Mesh::ATTRIBUTE_POSITION.at_shader_location(0)];
+
+        if key.shadow_pass {
+            shader_defs.push("SHADOW_PASS".into());
+        } else {
+            // Has to match the dithering of the main pass.
+            shader_defs.push("VISIBILITY_RANGE_DITHER".into());
+        }
+
+        // Directional light views need unclipped depth, emulated in the vertex shader if the
+        // GPU doesn't support it.
+        let unclipped_depth = mesh_key.contains(MeshPipelineKey::UNCLIPPED_DEPTH_ORTHO);
+        if unclipped_depth && !self.depth_clip_control_supported {
+            shader_defs.push("UNCLIPPED_DEPTH_ORTHO_EMULATION".into());
+        }
+
+        if normal_prepass {
+            shader_defs.push("NORMAL_PREPASS".into());
+
+            if layout.0.contains(Mesh::ATTRIBUTE_NORMAL) {
+                shader_defs.push("VERTEX_NORMALS".into());
+                vertex_attributes.push(Mesh::ATTRIBUTE_NORMAL.at_shader_location(1));
+            }
+        }
+        if motion_vector_prepass {
+            shader_defs.push("MOTION_VECTOR_PREPASS".into());
+        }
+        if key.previous_instances {
+            shader_defs.push("PREVIOUS_INSTANCE_DATA".into());
+        }
+        shader_defs.extend(key.instance_layout.shader_defs());
+        if key.batched {
+            shader_defs.extend(batched_shader_defs(self.instance_uniforms_stride));
+        }
+
+        // Alpha masked materials need their UVs to sample the alpha in the fragment shader.
+        let may_discard = mesh_key.contains(MeshPipelineKey::MAY_DISCARD);
+        if may_discard {
+            shader_defs.push("MAY_DISCARD".into());
+
+            if layout.0.contains(Mesh::ATTRIBUTE_UV_0) {
+                shader_defs.push("VERTEX_UVS".into());
+                shader_defs.push("VERTEX_UVS_A".into());
+                vertex_attributes.push(Mesh::ATTRIBUTE_UV_0.at_shader_location(2));
+            }
+        }
+
+        let targets = prepass_target_descriptors(normal_prepass, motion_vector_prepass, false);
+        let has_targets = targets.iter().any(Option::is_some);
+        if has_targets {
+            shader_defs.push("PREPASS_FRAGMENT".into());
+        }
+
+        // Shadow views only need a fragment shader to discard masked fragments.
+        let fragment = (!key.shadow_pass || may_discard).then(|| FragmentState {
+            shader: self.fragment_shader.clone(),
+            shader_defs: shader_defs.clone(),
+            targets: if has_targets { targets } else { vec![] },
+            ..default()
+        });
+
+        let view_layout = if motion_vector_prepass {
+            &self.view_layout_motion_vectors
+        } else {
+            &self.view_layout_no_motion_vectors
+        };
+
+        let vertex_buffer_layout = layout.0.get_layout(&vertex_attributes)?;
+
+        let mut descriptor = RenderPipelineDescriptor {
+            label: Some("instanced_material_prepass_pipeline".into()),
+            // The mesh bindings are unused, group 2 is bound to the empty bind group unless it
+            // holds the previous instances.
+            layout: vec![
+                view_layout.clone(),
+                self.empty_layout.clone(),
+                if key.previous_instances {
+                    self.previous_instances_layout.clone()
+                } else {
+                    self.empty_layout.clone()
                 },
-                // Index
-                VertexAttribute {
-                    format: VertexFormat::Uint32,
-                    offset: VertexFormat::Float32x4.size() + VertexFormat::Float32.size(),
-                    shader_location: 10,
+                if key.batched {
+                    self.batched_combined_layout
+                        .clone()
+                        .expect("batched draws require storage buffers in vertex shaders")
+                } else {
+                    self.combined_layout.clone()
                 },
             ],
-        });
+            vertex: VertexState {
+                shader: self.vertex_shader.clone(),
+                shader_defs,
+                buffers: vec![
+                    vertex_buffer_layout,
+                    instance_vertex_buffer_layout(key.instance_layout, key.batched),
+                ],
+                ..default()
+            },
+            fragment,
+            primitive: PrimitiveState {
+                topology: mesh_key.primitive_topology(),
+                cull_mode: Some(Face::Back),
+                unclipped_depth: unclipped_depth && self.depth_clip_control_supported,
+                ..default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: default(),
+                bias: default(),
+            }),
+            multisample: MultisampleState {
+                count: mesh_key.msaa_samples(),
+                ..default()
+            },
+            ..default()
+        };
+
+        M::specialize(&mut descriptor, layout, key.bind_group_data)?;
 
         Ok(descriptor)
     }
 }
 
+/// `BATCHED_INSTANCES` and the padding of its uniforms to the `stride` of the uniform buffer.
+fn batched_shader_defs(stride: u64) -> impl Iterator<Item = ShaderDefVal> {
+    let padding = (stride - size_of::<InstanceUniforms>() as u64) / 16;
+    core::iter::once("BATCHED_INSTANCES".into()).chain(
+        (padding > 0)
+            .then(|| ShaderDefVal::UInt("INSTANCE_UNIFORMS_PADDING".into(), padding as u32)),
+    )
+}
+
+fn instance_vertex_buffer_layout(layout: InstanceLayout, batched: bool) -> VertexBufferLayout {
+    let mut attributes = match layout.base {
+        InstanceBase::Compact => vec![
+            // Position + Scale
+            VertexAttribute {
+                format: VertexFormat::Float32x4,
+                offset: 0,
+                shader_location: 8,
+            },
+            // Rotation
+            VertexAttribute {
+                format: VertexFormat::Float32,
+                offset: VertexFormat::Float32x4.size(),
+                shader_location: 9,
+            },
+            // Index
+            VertexAttribute {
+                format: VertexFormat::Uint32,
+                offset: VertexFormat::Float32x4.size() + VertexFormat::Float32.size(),
+                shader_location: 10,
+            },
+            // Color
+            VertexAttribute {
+                format: VertexFormat::Unorm8x4,
+                offset: VertexFormat::Float32x4.size()
+                    + VertexFormat::Float32.size()
+                    + VertexFormat::Uint32.size(),
+                shader_location: 11,
+            },
+        ],
+        // Index and color keep their locations, see `OrientedInstanceData` for the offsets.
+        InstanceBase::Oriented => vec![
+            // Position
+            VertexAttribute {
+                format: VertexFormat::Float32x3,
+                offset: offset_of!(OrientedInstanceData, position) as u64,
+                shader_location: 8,
+            },
+            // Rotation
+            VertexAttribute {
+                format: VertexFormat::Float32x4,
+                offset: offset_of!(OrientedInstanceData, rotation) as u64,
+                shader_location: 9,
+            },
+            // Index
+            VertexAttribute {
+                format: VertexFormat::Uint32,
+                offset: offset_of!(OrientedInstanceData, index) as u64,
+                shader_location: 10,
+            },
+            // Color
+            VertexAttribute {
+                format: VertexFormat::Unorm8x4,
+                offset: offset_of!(OrientedInstanceData, color) as u64,
+                shader_location: 11,
+            },
+            // Scale
+            VertexAttribute {
+                format: VertexFormat::Float32x3,
+                offset: offset_of!(OrientedInstanceData, scale) as u64,
+                shader_location: 12,
+            },
+        ],
+    };
+    attributes.extend_from_slice(layout.attributes);
+
+    // Only instances of the arena are batched, which are always `InstanceData`.
+    if batched {
+        attributes.push(VertexAttribute {
+            format: VertexFormat::Uint32,
+            offset: offset_of!(InstanceData, uniform_index) as u64,
+            shader_location: 14,
+        });
+    }
+
+    VertexBufferLayout {
+        array_stride: layout.size,
+        step_mode: VertexStepMode::Instance,
+        attributes,
+    }
+}
+
 fn resolve_shader(
     asset_server: &AssetServer,
     shader_ref: ShaderRef,
@@ -1,22 +1,40 @@
+use crate::cull::{
+    pipeline::InstancedMaterialCullPipeline, prepare::prepare_instance_cull_arena,
+    queue::specialize_instanced_material_cull_pipelines,
+};
 use crate::prelude::*;
 use crate::render::{
-    draw::DrawInstancedMaterial, pipeline::InstancedMaterialPipeline, prepare::*,
-    prepared_material::PreparedInstancedMaterial, queue::*,
+    draw::{DrawInstancedMaterial, DrawInstancedPrepass, DrawInstancedShadow},
+    pipeline::{
+        InstancedMaterialPipeline, InstancedMaterialPrepassPipeline,
+        init_instanced_material_prepass_pipeline,
+    },
+    prepare::*,
+    prepared_material::PreparedInstancedMaterial,
+    queue::*,
 };
 
 use std::hash::Hash;
 use std::marker::PhantomData;
 
-use bevy_app::{App, Plugin};
+use bevy_app::{App, First, Plugin};
 use bevy_asset::{AssetApp, embedded_asset};
-use bevy_core_pipeline::core_3d::Opaque3d;
+use bevy_core_pipeline::{
+    core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+    prepass::{AlphaMask3dPrepass, Opaque3dPrepass},
+};
 use bevy_ecs::prelude::*;
+use bevy_pbr::{Shadow, init_prepass_pipeline};
 use bevy_render::{
-    Render, RenderApp, RenderSystems, extract_component::ExtractComponentPlugin,
-    render_asset::RenderAssetPlugin, render_graph::RenderLabel, render_phase::AddRenderCommand,
-    render_resource::SpecializedMeshPipelines,
+    ExtractSchedule, Render, RenderApp, RenderStartup, RenderSystems,
+    extract_component::ExtractComponentPlugin,
+    render_asset::{RenderAssetPlugin, prepare_assets},
+    render_graph::RenderLabel,
+    render_phase::AddRenderCommand,
+    render_resource::{SpecializedComputePipelines, SpecializedMeshPipelines},
+    sync_component::SyncComponentPlugin,
 };
-use bevy_shader::load_shader_library;
+use bevy_shader::{ShaderRef, load_shader_library};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub struct InstancedMaterialComputeLabel;
@@ -32,16 +50,27 @@ impl Plugin for InstancedMaterialCorePlugin {
 
         embedded_asset!(app, "mesh.wgsl");
         embedded_asset!(app, "shading.wgsl");
+        embedded_asset!(app, "prepass.wgsl");
 
-        app.add_plugins((ExtractComponentPlugin::<InstanceMaterialData>::default(),));
+        app.add_plugins((
+            SyncComponentPlugin::<InstanceMaterialData>::default(),
+            ExtractComponentPlugin::<InstanceShadowSettings>::default(),
+            ExtractComponentPlugin::<InstancePreviousData>::default(),
+        ));
+
+        // Runs after the previous frame was extracted.
+        app.add_systems(First, clear_instance_changes);
 
         let render_app = app.sub_app_mut(RenderApp);
 
-        render_app.add_systems(
-            Render,
-            ((prepare_instance_buffer, prepare_indirect_draw_buffer)
-                .in_set(RenderSystems::PrepareResources),),
-        );
+        render_app
+            .init_resource::<ExtractedInstanceMaterialData>()
+            .add_systems(ExtractSchedule, extract_instance_material_data)
+            .add_systems(
+                Render,
+                ((prepare_instance_buffer, prepare_indirect_draw_buffer)
+                    .in_set(RenderSystems::PrepareResources),),
+            );
     }
 }
 
@@ -62,7 +91,7 @@ where
         app.init_asset::<M>();
 
         app.add_plugins((
-            ExtractComponentPlugin::<InstancedMeshMaterial<M>>::default(),
+            SyncComponentPlugin::<InstancedMeshMaterial<M>>::default(),
             RenderAssetPlugin::<PreparedInstancedMaterial<M>>::default(),
         ));
 
@@ -70,18 +99,53 @@ where
 
         render_app
             .add_render_command::<Opaque3d, DrawInstancedMaterial<M>>()
+            .add_render_command::<AlphaMask3d, DrawInstancedMaterial<M>>()
+            .add_render_command::<Transparent3d, DrawInstancedMaterial<M>>()
+            .add_render_command::<Opaque3dPrepass, DrawInstancedPrepass<M>>()
+            .add_render_command::<AlphaMask3dPrepass, DrawInstancedPrepass<M>>()
+            .add_render_command::<Shadow, DrawInstancedShadow<M>>()
             .init_resource::<SpecializedMeshPipelines<InstancedMaterialPipeline<M>>>()
+            .init_resource::<SpecializedMeshPipelines<InstancedMaterialPrepassPipeline<M>>>()
+            .add_systems(ExtractSchedule, extract_instanced_mesh_materials::<M>)
+            .add_systems(
+                RenderStartup,
+                init_instanced_material_prepass_pipeline::<M>.after(init_prepass_pipeline),
+            )
             .add_systems(
                 Render,
                 (
                     queue_instanced_material::<M>.in_set(RenderSystems::QueueMeshes),
-                    prepare_instanced_bind_group::<M>.in_set(RenderSystems::PrepareResources),
+                    queue_instanced_material_prepass::<M>.in_set(RenderSystems::QueueMeshes),
+                    queue_instanced_material_shadows::<M>.in_set(RenderSystems::QueueMeshes),
+                    // The arena batches entities by their prepared uniforms.
+                    prepare_instanced_bind_group::<M>
+                        .in_set(RenderSystems::PrepareMeshes)
+                        .before(prepare_instance_cull_arena),
+                    prepare_previous_instance_buffers::<M>.in_set(RenderSystems::PrepareResources),
                 ),
             );
     }
 
     fn finish(&self, app: &mut App) {
-        app.sub_app_mut(RenderApp)
-            .init_resource::<InstancedMaterialPipeline<M>>();
+        let custom_cull_shader = app.is_plugin_added::<GpuComputeCullPlugin>()
+            && !matches!(M::cull_shader(), ShaderRef::Default);
+
+        let render_app = app.sub_app_mut(RenderApp);
+
+        render_app
+            .init_resource::<InstancedMaterialPipeline<M>>()
+            .init_resource::<InstanceUniformsBuffer<M>>();
+
+        if custom_cull_shader {
+            render_app
+                .init_resource::<InstancedMaterialCullPipeline<M>>()
+                .init_resource::<SpecializedComputePipelines<InstancedMaterialCullPipeline<M>>>()
+                .add_systems(
+                    Render,
+                    specialize_instanced_material_cull_pipelines::<M>
+                        .after(prepare_assets::<PreparedInstancedMaterial<M>>)
+                        .in_set(RenderSystems::PrepareAssets),
+                );
+        }
     }
 }
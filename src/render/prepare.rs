@@ -1,6 +1,7 @@
 use crate::prelude::*;
 use crate::render::{
-    pipeline::InstancedMaterialPipeline, prepared_material::PreparedInstancedMaterial,
+    pipeline::{InstancedMaterialPipeline, InstancedMaterialPrepassPipeline},
+    prepared_material::PreparedInstancedMaterial,
 };
 
 use bevy_ecs::prelude::*;
@@ -8,9 +9,10 @@ use bevy_pbr::RenderMeshInstances;
 use bevy_render::{
     mesh::allocator::MeshAllocator,
     mesh::{RenderMesh, RenderMeshBufferInfo},
-    render_asset::RenderAssets,
+    render_asset::{ExtractedAssets, RenderAssets},
     render_resource::{
-        BindGroupEntry, BufferInitDescriptor, BufferUsages, DrawIndexedIndirectArgs,
+        BindGroup, BindGroupEntries, BindGroupEntry, BindingResource, Buffer, BufferBinding,
+        BufferDescriptor, BufferInitDescriptor, BufferUsages, DrawIndexedIndirectArgs,
     },
     renderer::{RenderDevice, RenderQueue},
     sync_world::MainEntity,
@@ -19,126 +21,430 @@ use bevy_transform::components::GlobalTransform;
 
 use bytemuck::bytes_of;
 
+use std::num::NonZeroU64;
+use std::ops::Range;
+
 pub(crate) fn prepare_instance_buffer(
     mut cmd: Commands,
-    query: Query<(Entity, &InstanceMaterialData, Option<&InstanceBuffer>), Without<GpuCullCompute>>,
+    mut query: Query<
+        (
+            Entity,
+            Ref<InstanceMaterialData>,
+            Option<&mut InstanceBuffer>,
+        ),
+        Without<GpuCullCompute>,
+    >,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
 ) {
-    for (entity, instance_data, instance_buffer) in &query {
-        let instance_vec = &instance_data.instances;
+    for (entity, instance_data, instance_buffer) in &mut query {
+        let instances = &instance_data.instances;
 
-        let Some(instance_buffer) = instance_buffer else {
-            create_buffer(&mut cmd, entity, instance_vec, &render_device);
-            continue;
-        };
-
-        if instance_vec.len() != instance_buffer.length {
-            create_buffer(&mut cmd, entity, instance_vec, &render_device);
+        // The size also changes with the layout. Left over output buffers of the GPU cull pass
+        // can't be written to.
+        if let Some(mut instance_buffer) = instance_buffer
+            && instances.bytes().len() as u64 <= instance_buffer.buffer.size()
+            && instance_buffer
+                .buffer
+                .usage()
+                .contains(BufferUsages::COPY_DST)
+        {
+            // Unchanged data isn't extracted again, it was uploaded in an earlier frame.
+            if instance_data.is_changed() {
+                write_instance_changes(&render_queue, &instance_buffer.buffer, instances);
+            }
+            instance_buffer.length = instances.len();
+            instance_buffer.capacity =
+                (instance_buffer.buffer.size() / instances.layout().size) as usize;
             continue;
         }
 
-        render_queue.write_buffer(
-            &instance_buffer.buffer,
-            0,
-            bytemuck::cast_slice(instance_vec.as_slice()),
-        );
+        create_buffer(&mut cmd, entity, instances, &render_device, &render_queue);
     }
 }
 
 fn create_buffer(
     cmd: &mut Commands,
     entity: Entity,
-    instance_vec: &Vec<InstanceData>,
-    render_device: &Res<RenderDevice>,
+    instances: &Instances,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
 ) {
-    let contents = bytemuck::cast_slice(instance_vec.as_slice());
+    let capacity = instance_capacity(instances.len());
 
-    let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+    let buffer = render_device.create_buffer(&BufferDescriptor {
         label: Some("instanced_material_data_buffer"),
-        contents,
+        size: capacity as u64 * instances.layout().size,
         usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
     });
+    render_queue.write_buffer(&buffer, 0, instances.bytes());
 
     cmd.entity(entity).insert(InstanceBuffer {
         buffer,
-        length: instance_vec.len(),
+        length: instances.len(),
+        capacity,
+        view_stride: 0,
+        lod_stride: 0,
     });
 }
 
+/// Uploads the [`InstanceChanges`] to a buffer holding the previously extracted instances.
+pub(crate) fn write_instance_changes(
+    render_queue: &RenderQueue,
+    buffer: &Buffer,
+    instances: &Instances,
+) {
+    let size = instances.layout().size as usize;
+    match instances.changes() {
+        InstanceChanges::All => render_queue.write_buffer(buffer, 0, instances.bytes()),
+        InstanceChanges::Ranges(ranges) => {
+            for range in ranges {
+                let bytes = &instances.bytes()[range.start * size..range.end * size];
+                render_queue.write_buffer(buffer, (range.start * size) as u64, bytes);
+            }
+        }
+    }
+}
+
 pub const INSTANCE_BINDING_INDEX: u32 = 100;
 
+/// Packs the [`InstanceUniforms`] of all entities with material `M` into the
+/// [`InstanceUniformsBuffer`] and assigns the shared [`InstancedCombinedBindGroup`] of their
+/// material.
+///
+/// Only the uniforms of changed entities are prepared and uploaded again, unless the packing of
+/// the entities changed. Bind groups are only created for new materials or after the buffer grew.
 pub(crate) fn prepare_instanced_bind_group<M>(
     mut commands: Commands,
-    query: Query<(
+    mut query: Query<(
         Entity,
-        &InstancedMeshMaterial<M>,
-        &InstanceMaterialData,
-        &GlobalTransform,
-        Option<&InstanceUniformBuffer>,
+        Ref<InstancedMeshMaterial<M>>,
+        Ref<InstanceMaterialData>,
+        Ref<GlobalTransform>,
+        Option<&InstanceShadowSettings>,
+        Option<&mut PreparedInstanceUniforms>,
+        Option<&mut InstancedCombinedBindGroup>,
     )>,
     render_materials: Res<RenderAssets<PreparedInstancedMaterial<M>>>,
+    extracted_materials: Res<ExtractedAssets<PreparedInstancedMaterial<M>>>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     pipeline: Res<InstancedMaterialPipeline<M>>,
+    mut uniforms_buffer: ResMut<InstanceUniformsBuffer<M>>,
 ) where
     M: InstancedMaterial,
 {
-    for (entity, material_handle, instance_data, gtf, uniform_buffer) in &query {
+    // Entities whose material isn't prepared have no uniforms.
+    let packing: Vec<Entity> = query
+        .iter()
+        .filter(|(_, material_handle, ..)| render_materials.get(&material_handle.0).is_some())
+        .map(|(entity, ..)| entity)
+        .collect();
+    if packing.is_empty() {
+        return;
+    }
+
+    let uniforms_buffer = uniforms_buffer.as_mut();
+    let stride = uniforms_buffer.stride;
+
+    let buffer = match &uniforms_buffer.buffer {
+        Some(buffer) if buffer.size() >= packing.len() as u64 * stride => buffer.clone(),
+        _ => {
+            // Batched draws bind the whole buffer as an array of uniforms.
+            let storage = if pipeline.batched_combined_layout.is_some() {
+                BufferUsages::STORAGE
+            } else {
+                BufferUsages::empty()
+            };
+            let buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("instanced_material_uniform_buffer"),
+                size: instance_capacity(packing.len()) as u64 * stride,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST | storage,
+                mapped_at_creation: false,
+            });
+            uniforms_buffer.buffer = Some(buffer.clone());
+            uniforms_buffer.packing.clear();
+            uniforms_buffer.bind_groups.clear();
+            buffer
+        }
+    };
+
+    // Added or removed entities move the uniforms of others.
+    let repack = packing != uniforms_buffer.packing;
+    if repack {
+        uniforms_buffer
+            .contents
+            .resize(packing.len() * stride as usize, 0);
+        uniforms_buffer.packing = packing;
+    }
+
+    // Modified materials have new bindings.
+    uniforms_buffer.bind_groups.retain(|id, _| {
+        !extracted_materials.added.contains(id) && render_materials.get(*id).is_some()
+    });
+
+    let mut changed_ranges: Vec<Range<usize>> = Vec::new();
+    let mut index = 0;
+
+    for (
+        entity,
+        material_handle,
+        instance_data,
+        gtf,
+        shadow_settings,
+        prepared_uniforms,
+        combined_bind_group,
+    ) in &mut query
+    {
         let Some(prepared_material) = render_materials.get(&material_handle.0) else {
+            // Modified materials are removed until they are prepared again.
+            if combined_bind_group.is_some() {
+                commands
+                    .entity(entity)
+                    .remove::<InstancedCombinedBindGroup>();
+            }
             continue;
         };
 
+        let offset = index * stride as u32;
+        index += 1;
+
+        let material_id = material_handle.0.id();
+        let shadow_density = shadow_settings.map_or(1.0, |settings| settings.density);
+
+        let changed = match (&prepared_uniforms, &combined_bind_group) {
+            (Some(prepared), Some(_)) => {
+                repack
+                    || material_handle.is_changed()
+                    || instance_data.is_changed()
+                    || gtf.is_changed()
+                    || extracted_materials.added.contains(&material_id)
+                    || prepared.uniforms.shadow_density != shadow_density
+                    // Uploads once more after a transform stopped changing, to catch up
+                    // `previous_world_from_local`.
+                    || prepared.uniforms.previous_world_from_local
+                        != prepared.uniforms.world_from_local
+            }
+            _ => true,
+        };
+        if !changed {
+            continue;
+        }
+
+        let world_from_local = gtf.to_matrix();
+
         let uniforms = InstanceUniforms {
-            world_from_local: gtf.to_matrix(),
-            ..instance_data.into()
+            world_from_local,
+            // The first frame has no previous transform.
+            previous_world_from_local: prepared_uniforms
+                .as_ref()
+                .map_or(world_from_local, |prepared| {
+                    prepared.uniforms.world_from_local
+                }),
+            shadow_density,
+            alpha_cutoff: prepared_material.alpha_mode.cutoff(),
+            ..instance_data.as_ref().into()
         };
-        let contents = bytes_of(&uniforms);
-
-        let buffer = uniform_buffer
-            .map(|InstanceUniformBuffer { buffer }| {
-                render_queue.write_buffer(buffer, 0, contents);
-                buffer.clone()
-            })
-            .unwrap_or_else(|| {
-                let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-                    label: Some("instanced_material_uniform_buffer"),
-                    contents,
-                    usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-                });
 
-                commands.entity(entity).insert(InstanceUniformBuffer {
-                    buffer: buffer.clone(),
+        let start = offset as usize;
+        let bytes = bytes_of(&uniforms);
+        uniforms_buffer.contents[start..start + bytes.len()].copy_from_slice(bytes);
+        match changed_ranges.last_mut() {
+            Some(range) if range.end == start => range.end = start + stride as usize,
+            _ => changed_ranges.push(start..start + stride as usize),
+        }
+
+        let index = offset / stride as u32;
+        let material = material_id.untyped();
+        let blend = prepared_material.alpha_mode == InstancedAlphaMode::Blend;
+
+        match prepared_uniforms {
+            Some(mut prepared) => {
+                prepared.uniforms = uniforms;
+                prepared.offset = offset;
+                prepared.index = index;
+                prepared.material = material;
+                prepared.blend = blend;
+            }
+            None => {
+                commands.entity(entity).insert(PreparedInstanceUniforms {
+                    uniforms,
+                    offset,
+                    index,
+                    material,
+                    blend,
                 });
+            }
+        }
+
+        let bind_group = uniforms_buffer
+            .bind_groups
+            .entry(material_id)
+            .or_insert_with(|| {
+                let create_bind_group = |layout, instance_binding| {
+                    let entries: Vec<BindGroupEntry> = prepared_material
+                        .bindings
+                        .iter()
+                        .map(|(index, resource)| BindGroupEntry {
+                            binding: *index,
+                            resource: resource.get_binding(),
+                        })
+                        .chain(std::iter::once(BindGroupEntry {
+                            binding: INSTANCE_BINDING_INDEX,
+                            resource: instance_binding,
+                        }))
+                        .collect();
 
-                buffer
+                    render_device.create_bind_group(
+                        "instanced_material_combined_bind_group",
+                        layout,
+                        &entries,
+                    )
+                };
+
+                InstancedCombinedBindGroup {
+                    bind_group: create_bind_group(
+                        &pipeline.combined_layout,
+                        BindingResource::Buffer(BufferBinding {
+                            buffer: &buffer,
+                            offset: 0,
+                            size: NonZeroU64::new(size_of::<InstanceUniforms>() as u64),
+                        }),
+                    ),
+                    batched_bind_group: pipeline
+                        .batched_combined_layout
+                        .as_ref()
+                        .map(|layout| create_bind_group(layout, buffer.as_entire_binding())),
+                }
             });
 
-        let entries: Vec<BindGroupEntry> = prepared_material
-            .bindings
-            .iter()
-            .map(|(index, resource)| BindGroupEntry {
-                binding: *index,
-                resource: resource.get_binding(),
-            })
-            .chain(std::iter::once(BindGroupEntry {
-                binding: INSTANCE_BINDING_INDEX,
-                resource: buffer.as_entire_binding(),
-            }))
-            .collect();
-
-        let bind_group = render_device.create_bind_group(
-            "instanced_material_combined_bind_group",
-            &pipeline.combined_layout,
-            &entries,
+        match combined_bind_group {
+            Some(mut combined_bind_group) => {
+                if combined_bind_group.bind_group.id() != bind_group.bind_group.id() {
+                    *combined_bind_group = bind_group.clone();
+                }
+            }
+            None => {
+                commands.entity(entity).insert(bind_group.clone());
+            }
+        }
+    }
+
+    for range in changed_ranges {
+        render_queue.write_buffer(
+            &buffer,
+            range.start as u64,
+            &uniforms_buffer.contents[range],
         );
+    }
+}
 
-        commands
-            .entity(entity)
-            .insert(InstancedCombinedBindGroup(bind_group));
+/// Uploads the instances of the previous frame of entities with [`InstancePreviousData`].
+pub(crate) fn prepare_previous_instance_buffers<M: InstancedMaterial>(
+    mut commands: Commands,
+    mut query: Query<
+        (
+            Entity,
+            Ref<InstanceMaterialData>,
+            Option<&mut PreviousInstanceBuffer>,
+        ),
+        (With<InstancedMeshMaterial<M>>, With<InstancePreviousData>),
+    >,
+    removed: Query<
+        Entity,
+        (
+            With<InstancedMeshMaterial<M>>,
+            With<PreviousInstanceBuffer>,
+            Without<InstancePreviousData>,
+        ),
+    >,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline: Res<InstancedMaterialPrepassPipeline<M>>,
+) {
+    // The prepass binds the buffer as long as it exists.
+    for entity in &removed {
+        commands.entity(entity).remove::<PreviousInstanceBuffer>();
+    }
+
+    for (entity, instance_data, previous_buffer) in &mut query {
+        let instances = &instance_data.instances;
+        if instances.is_empty() {
+            continue;
+        }
+
+        if let Some(mut previous_buffer) = previous_buffer
+            && previous_buffer.instances.layout() == instances.layout()
+            && previous_buffer.instances.len() <= previous_buffer.capacity
+        {
+            // The buffer holds the instances of two frames ago, which the previous frame's
+            // changes are relative to.
+            let previous = previous_buffer.instances.clone();
+            write_instance_changes(&render_queue, &previous_buffer.buffer, &previous);
+
+            // Instances added since the previous frame are out of bounds of the binding.
+            if previous.len() != previous_buffer.length {
+                previous_buffer.bind_group = previous_instance_bind_group(
+                    &render_device,
+                    &pipeline,
+                    &previous_buffer.buffer,
+                    &previous,
+                );
+                previous_buffer.length = previous.len();
+            }
+
+            previous_buffer.instances = instances.clone();
+            // Nothing changed since the previous instances, which the buffer holds after this
+            // upload.
+            if !instance_data.is_changed() {
+                previous_buffer.instances.clear_changes();
+            }
+            continue;
+        }
+
+        // Starts over from the current instances in the first frame and after the buffer was
+        // outgrown.
+        let capacity = instance_capacity(instances.len());
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("instanced_material_previous_instance_buffer"),
+            size: capacity as u64 * instances.layout().size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        render_queue.write_buffer(&buffer, 0, instances.bytes());
+
+        let bind_group =
+            previous_instance_bind_group(&render_device, &pipeline, &buffer, instances);
+
+        commands.entity(entity).insert(PreviousInstanceBuffer {
+            buffer,
+            bind_group,
+            length: instances.len(),
+            capacity,
+            instances: instances.clone(),
+        });
     }
 }
 
+/// Binds the first `instances` of the buffer, the prepass bounds checks against their count.
+fn previous_instance_bind_group<M: InstancedMaterial>(
+    render_device: &RenderDevice,
+    pipeline: &InstancedMaterialPrepassPipeline<M>,
+    buffer: &Buffer,
+    instances: &Instances,
+) -> BindGroup {
+    render_device.create_bind_group(
+        "instanced_material_previous_instance_bind_group",
+        &pipeline.previous_instances_layout,
+        &BindGroupEntries::single(BufferBinding {
+            buffer,
+            offset: 0,
+            size: NonZeroU64::new(instances.bytes().len() as u64),
+        }),
+    )
+}
+
 pub fn prepare_indirect_draw_buffer(
     mut cmd: Commands,
     query: Query<
@@ -190,8 +496,12 @@ pub fn prepare_indirect_draw_buffer(
                 usage: BufferUsages::INDIRECT | BufferUsages::COPY_DST,
             });
 
-            cmd.entity(entity)
-                .insert(GpuDrawIndexedIndirect { buffer, offset: 0 });
+            cmd.entity(entity).insert(GpuDrawIndexedIndirect {
+                buffer,
+                offset: 0,
+                view_stride: 0,
+                lod_stride: 0,
+            });
         }
     }
 }
@@ -3,17 +3,26 @@ use bevy_ecs::system::SystemParamItem;
 use bevy_ecs::system::lifetimeless::SRes;
 use bevy_render::{
     render_asset::{PrepareAssetError, RenderAsset},
-    render_resource::{AsBindGroup, AsBindGroupError, OwnedBindingResource},
+    render_resource::{
+        AsBindGroup, AsBindGroupError, BindGroup, BindGroupEntry, OwnedBindingResource,
+    },
     renderer::RenderDevice,
 };
+use bevy_shader::ShaderRef;
 use std::marker::PhantomData;
 
-use crate::material::InstancedMaterial;
+use crate::material::{InstancedAlphaMode, InstancedMaterial};
 use crate::render::pipeline::InstancedMaterialPipeline;
 
 pub struct PreparedInstancedMaterial<M: InstancedMaterial> {
     pub bindings: Vec<(u32, OwnedBindingResource)>,
     pub key: M::Data,
+    /// Material bindings for a custom [`InstancedMaterial::cull_shader`], bound at group 2.
+    pub cull_bind_group: Option<BindGroup>,
+    /// See [`InstancedMaterial::casts_shadows`].
+    pub casts_shadows: bool,
+    /// See [`InstancedMaterial::alpha_mode`].
+    pub alpha_mode: InstancedAlphaMode,
     _phantom: PhantomData<M>,
 }
 
@@ -22,6 +31,9 @@ impl<M: InstancedMaterial> PreparedInstancedMaterial<M> {
         Self {
             bindings,
             key,
+            cull_bind_group: None,
+            casts_shadows: true,
+            alpha_mode: InstancedAlphaMode::Opaque,
             _phantom: PhantomData,
         }
     }
@@ -47,11 +59,35 @@ impl<M: InstancedMaterial> RenderAsset for PreparedInstancedMaterial<M> {
             material_params,
             false,
         ) {
-            Ok(unprepared) => Ok(PreparedInstancedMaterial {
-                key: source_asset.bind_group_data(),
-                bindings: unprepared.bindings.0,
-                _phantom: PhantomData,
-            }),
+            Ok(unprepared) => {
+                let cull_bind_group =
+                    (!matches!(M::cull_shader(), ShaderRef::Default)).then(|| {
+                        let entries: Vec<BindGroupEntry> = unprepared
+                            .bindings
+                            .0
+                            .iter()
+                            .map(|(index, resource)| BindGroupEntry {
+                                binding: *index,
+                                resource: resource.get_binding(),
+                            })
+                            .collect();
+
+                        render_device.create_bind_group(
+                            "instanced_material_cull_bind_group",
+                            &pipeline.material_layout,
+                            &entries,
+                        )
+                    });
+
+                Ok(PreparedInstancedMaterial {
+                    key: source_asset.bind_group_data(),
+                    bindings: unprepared.bindings.0,
+                    cull_bind_group,
+                    casts_shadows: source_asset.casts_shadows(),
+                    alpha_mode: source_asset.alpha_mode(),
+                    _phantom: PhantomData,
+                })
+            }
             Err(AsBindGroupError::RetryNextUpdate) => {
                 Err(PrepareAssetError::RetryNextUpdate(source_asset))
             }
@@ -0,0 +1,175 @@
+#import bevy_pbr::mesh_view_bindings::view
+#ifdef MOTION_VECTOR_PREPASS
+#import bevy_pbr::prepass_bindings::previous_view_uniforms
+#endif
+
+#import bevy_eidolon::render::bindings::instance_uniforms
+#ifdef BATCHED_INSTANCES
+#import bevy_eidolon::render::bindings::load_instance_uniforms
+#endif
+#ifdef PREVIOUS_INSTANCE_DATA
+#import bevy_eidolon::render::bindings::previous_instances
+#endif
+#import bevy_eidolon::render::utils
+#import bevy_eidolon::render::io_types::{PrepassVertexOutput, Vertex}
+#ifdef PREPASS_FRAGMENT
+#import bevy_eidolon::render::io_types::PrepassFragmentOutput
+#endif
+
+
+@vertex
+fn vertex(vertex: Vertex) -> PrepassVertexOutput {
+    var out: PrepassVertexOutput;
+
+#ifdef BATCHED_INSTANCES
+    load_instance_uniforms(vertex.i_uniform_index);
+    out.uniform_index = vertex.i_uniform_index;
+#endif
+
+#ifdef SHADOW_PASS
+    // Dropped instances collapse into a single point, which rasterizes nothing.
+    if (utils::hash_noise(vertex.i_index) >= instance_uniforms.shadow_density) {
+        out.clip_position = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return out;
+    }
+#endif
+
+#ifdef ORIENTED_INSTANCES
+    let final_matrix = utils::calculate_oriented_instance_world_matrix(
+        vertex.i_position,
+        vertex.i_rotation,
+        vertex.i_scale,
+        instance_uniforms.world_from_local
+    );
+#else
+    let final_matrix = utils::calculate_instance_world_matrix(vertex.i_pos_scale, vertex.i_rotation, instance_uniforms.world_from_local);
+#endif
+
+    // Same order of operations as the main pass, so the depth matches exactly.
+    let world_position = final_matrix * vec4<f32>(vertex.position, 1.0);
+
+    out.clip_position = view.clip_from_world * world_position;
+
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    // Clamp casters in front of the near plane of directional light views onto it.
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+#endif
+
+#ifdef NORMAL_PREPASS
+#ifdef VERTEX_NORMALS
+    out.world_normal = normalize((final_matrix * vec4<f32>(vertex.normal, 0.0)).xyz);
+#else
+    out.world_normal = vec3<f32>(0.0, 1.0, 0.0);
+#endif
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    out.world_position = world_position;
+
+#ifdef ORIENTED_INSTANCES
+    var previous_position = vertex.i_position;
+    var previous_rotation = vertex.i_rotation;
+    var previous_scale = vertex.i_scale;
+#ifdef PREVIOUS_INSTANCE_DATA
+    // Instances added this frame have no previous data.
+    if (vertex.i_index < arrayLength(&previous_instances)) {
+        let previous = previous_instances[vertex.i_index];
+        previous_position = previous.position;
+        previous_rotation = previous.rotation;
+        previous_scale = previous.scale;
+    }
+#endif
+
+    let previous_matrix = utils::calculate_oriented_instance_world_matrix(
+        previous_position,
+        previous_rotation,
+        previous_scale,
+        instance_uniforms.previous_world_from_local
+    );
+#else
+    var previous_pos_scale = vertex.i_pos_scale;
+    var previous_rotation = vertex.i_rotation;
+#ifdef PREVIOUS_INSTANCE_DATA
+    // Instances added this frame have no previous data.
+    if (vertex.i_index < arrayLength(&previous_instances)) {
+        let previous = previous_instances[vertex.i_index];
+        previous_pos_scale = previous.pos_and_scale;
+        previous_rotation = previous.rotation;
+    }
+#endif
+
+    let previous_matrix = utils::calculate_instance_world_matrix(
+        previous_pos_scale,
+        previous_rotation,
+        instance_uniforms.previous_world_from_local
+    );
+#endif
+    out.previous_world_position = previous_matrix * vec4<f32>(vertex.position, 1.0);
+#endif
+
+#ifdef VERTEX_UVS_A
+    out.uv = vertex.uv;
+#endif
+
+#ifdef MAY_DISCARD
+    out.color = vertex.i_color;
+#endif
+
+#ifdef VISIBILITY_RANGE_DITHER
+    out.visibility_range_dither = utils::get_visibility_range_dither_level(
+        instance_uniforms.visibility_range,
+        final_matrix[3]
+    );
+#endif
+
+    return out;
+}
+
+// Discards the same fragments as the main pass.
+fn prepass_discard(in: PrepassVertexOutput) {
+#ifdef VISIBILITY_RANGE_DITHER
+    bevy_pbr::pbr_functions::visibility_range_dither(in.clip_position, in.visibility_range_dither);
+#endif
+
+#ifdef MAY_DISCARD
+    if (instance_uniforms.color.a * in.color.a < instance_uniforms.alpha_cutoff) {
+        discard;
+    }
+#endif
+}
+
+#ifdef PREPASS_FRAGMENT
+@fragment
+fn fragment(in: PrepassVertexOutput) -> PrepassFragmentOutput {
+    var out: PrepassFragmentOutput;
+
+#ifdef BATCHED_INSTANCES
+    load_instance_uniforms(in.uniform_index);
+#endif
+
+    prepass_discard(in);
+
+#ifdef NORMAL_PREPASS
+    out.normal = vec4<f32>(in.world_normal * 0.5 + vec3<f32>(0.5), 1.0);
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    let clip_position_t = view.unjittered_clip_from_world * in.world_position;
+    let clip_position = clip_position_t.xy / clip_position_t.w;
+    let previous_clip_position_t = previous_view_uniforms.clip_from_world * in.previous_world_position;
+    let previous_clip_position = previous_clip_position_t.xy / previous_clip_position_t.w;
+    out.motion_vector = (clip_position - previous_clip_position) * vec2<f32>(0.5, -0.5);
+#endif
+
+    return out;
+}
+#else
+@fragment
+fn fragment(in: PrepassVertexOutput) {
+#ifdef BATCHED_INSTANCES
+    load_instance_uniforms(in.uniform_index);
+#endif
+
+    prepass_discard(in);
+}
+#endif
@@ -1,23 +1,35 @@
 use crate::prelude::*;
 use crate::render::{
-    draw::DrawInstancedMaterial,
-    pipeline::{InstancedMaterialPipeline, InstancedMaterialPipelineKey},
+    draw::{DrawInstancedMaterial, DrawInstancedPrepass, DrawInstancedShadow},
+    pipeline::{
+        InstancedMaterialPipeline, InstancedMaterialPipelineKey, InstancedMaterialPrepassPipeline,
+        InstancedMaterialPrepassPipelineKey,
+    },
     prepared_material::PreparedInstancedMaterial,
 };
 
+use bevy_camera::visibility::RenderLayers;
 use bevy_core_pipeline::{
-    core_3d::{Opaque3d, Opaque3dBatchSetKey, Opaque3dBinKey},
-    prepass::{DepthPrepass, MotionVectorPrepass, NormalPrepass},
+    core_3d::{AlphaMask3d, Opaque3d, Opaque3dBatchSetKey, Opaque3dBinKey, Transparent3d},
+    prepass::{
+        AlphaMask3dPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass, Opaque3dPrepass,
+        OpaqueNoLightmap3dBatchSetKey, OpaqueNoLightmap3dBinKey,
+    },
 };
 use bevy_ecs::{prelude::*, system::SystemChangeTick};
-use bevy_pbr::{MeshPipelineKey, RenderMeshInstances};
+use bevy_pbr::{
+    LightEntity, MeshPipelineKey, RenderMeshInstanceFlags, RenderMeshInstances, Shadow,
+    ShadowBatchSetKey, ShadowBinKey, ViewLightEntities,
+};
 use bevy_render::{
     batching::gpu_preprocessing::GpuPreprocessingSupport,
     mesh::RenderMesh,
     mesh::allocator::MeshAllocator,
     render_asset::RenderAssets,
     render_phase::DrawFunctions,
-    render_phase::{BinnedRenderPhaseType, ViewBinnedRenderPhases},
+    render_phase::{
+        BinnedRenderPhaseType, PhaseItemExtraIndex, ViewBinnedRenderPhases, ViewSortedRenderPhases,
+    },
     render_resource::*,
     sync_world::MainEntity,
     view::ExtractedView,
@@ -29,20 +41,29 @@ use std::hash::Hash;
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn queue_instanced_material<M>(
     opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<InstancedMaterialPipeline<M>>,
     mut pipelines: ResMut<SpecializedMeshPipelines<InstancedMaterialPipeline<M>>>,
     pipeline_cache: Res<PipelineCache>,
     meshes: Res<RenderAssets<RenderMesh>>,
     render_mesh_instances: Res<RenderMeshInstances>,
     render_materials: Res<RenderAssets<PreparedInstancedMaterial<M>>>,
-    material_meshes: Query<
-        (Entity, &MainEntity, &InstancedMeshMaterial<M>),
-        With<InstanceMaterialData>,
-    >,
+    material_meshes: Query<(
+        Entity,
+        &MainEntity,
+        &InstancedMeshMaterial<M>,
+        &InstanceMaterialData,
+    )>,
     mesh_allocator: Res<MeshAllocator>,
     gpu_preprocessing_support: Res<GpuPreprocessingSupport>,
-    mut opaque_render_phases: ResMut<ViewBinnedRenderPhases<Opaque3d>>,
+    (mut opaque_render_phases, mut alpha_mask_render_phases, mut transparent_render_phases): (
+        ResMut<ViewBinnedRenderPhases<Opaque3d>>,
+        ResMut<ViewBinnedRenderPhases<AlphaMask3d>>,
+        ResMut<ViewSortedRenderPhases<Transparent3d>>,
+    ),
     ticks: SystemChangeTick,
+    arena: Option<Res<InstanceCullArena>>,
     views: Query<(
         &ExtractedView,
         &Msaa,
@@ -57,13 +78,24 @@ pub(crate) fn queue_instanced_material<M>(
     let draw_custom = opaque_3d_draw_functions
         .read()
         .id::<DrawInstancedMaterial<M>>();
+    let draw_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .id::<DrawInstancedMaterial<M>>();
+    let draw_transparent = transparent_3d_draw_functions
+        .read()
+        .id::<DrawInstancedMaterial<M>>();
 
     for (view, msaa, depth_prepass, normal_prepass, motion_vector_prepass) in &views {
-        let Some(opaque_mask_phases) = opaque_render_phases.get_mut(&view.retained_view_entity)
-        else {
+        let (Some(opaque_phase), Some(alpha_mask_phase), Some(transparent_phase)) = (
+            opaque_render_phases.get_mut(&view.retained_view_entity),
+            alpha_mask_render_phases.get_mut(&view.retained_view_entity),
+            transparent_render_phases.get_mut(&view.retained_view_entity),
+        ) else {
             continue;
         };
 
+        let rangefinder = view.rangefinder3d();
+
         let mut view_key = MeshPipelineKey::from_msaa_samples(msaa.samples())
             | MeshPipelineKey::from_hdr(view.hdr);
 
@@ -77,7 +109,7 @@ pub(crate) fn queue_instanced_material<M>(
             view_key |= MeshPipelineKey::MOTION_VECTOR_PREPASS;
         }
 
-        for (entity, main_entity, h_material) in &material_meshes {
+        for (entity, main_entity, h_material, instance_data) in &material_meshes {
             let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(*main_entity)
             else {
                 continue;
@@ -89,9 +121,20 @@ pub(crate) fn queue_instanced_material<M>(
                 continue;
             };
 
+            // Followers are drawn by the leader of their batch.
+            let arena_chunk = arena.as_ref().and_then(|arena| arena.chunks.get(&entity));
+            if arena_chunk.is_some_and(|chunk| chunk.batch_draw_count == 0) {
+                continue;
+            }
+
+            let alpha_mode = prepared_material.alpha_mode;
+
             let key = InstancedMaterialPipelineKey {
                 mesh_key: view_key
-                    | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology()),
+                    | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology())
+                    | alpha_mode.pipeline_key(),
+                instance_layout: instance_data.instances.layout(),
+                batched: arena_chunk.is_some(),
                 bind_group_data: prepared_material.key.clone(),
             };
 
@@ -100,27 +143,339 @@ pub(crate) fn queue_instanced_material<M>(
                 .unwrap();
 
             let (vertex_slab, index_slab) = mesh_allocator.mesh_slabs(&mesh_instance.mesh_asset_id);
+            let phase_type = BinnedRenderPhaseType::mesh(
+                mesh_instance.should_batch(),
+                &gpu_preprocessing_support,
+            );
 
-            opaque_mask_phases.add(
-                Opaque3dBatchSetKey {
-                    pipeline,
-                    draw_function: draw_custom,
-                    material_bind_group_index: None,
-                    vertex_slab: vertex_slab.unwrap_or_default(),
-                    index_slab,
-                    lightmap_slab: None,
-                },
-                Opaque3dBinKey {
-                    asset_id: mesh_instance.mesh_asset_id.into(),
-                },
-                (entity, *main_entity),
-                mesh_instance.current_uniform_index,
-                BinnedRenderPhaseType::mesh(
-                    mesh_instance.should_batch(),
-                    &gpu_preprocessing_support,
+            match alpha_mode {
+                InstancedAlphaMode::Opaque => opaque_phase.add(
+                    Opaque3dBatchSetKey {
+                        pipeline,
+                        draw_function: draw_custom,
+                        material_bind_group_index: None,
+                        vertex_slab: vertex_slab.unwrap_or_default(),
+                        index_slab,
+                        lightmap_slab: None,
+                    },
+                    Opaque3dBinKey {
+                        asset_id: mesh_instance.mesh_asset_id.into(),
+                    },
+                    (entity, *main_entity),
+                    mesh_instance.current_uniform_index,
+                    phase_type,
+                    ticks.this_run(),
                 ),
-                ticks.this_run(),
+                InstancedAlphaMode::Mask(_) => alpha_mask_phase.add(
+                    OpaqueNoLightmap3dBatchSetKey {
+                        pipeline,
+                        draw_function: draw_alpha_mask,
+                        material_bind_group_index: None,
+                        vertex_slab: vertex_slab.unwrap_or_default(),
+                        index_slab,
+                    },
+                    OpaqueNoLightmap3dBinKey {
+                        asset_id: mesh_instance.mesh_asset_id.into(),
+                    },
+                    (entity, *main_entity),
+                    mesh_instance.current_uniform_index,
+                    phase_type,
+                    ticks.this_run(),
+                ),
+                // Sorted by the entity's translation, instances are spread around it.
+                InstancedAlphaMode::Blend => transparent_phase.add(Transparent3d {
+                    distance: rangefinder.distance_translation(&mesh_instance.translation),
+                    pipeline,
+                    entity: (entity, *main_entity),
+                    draw_function: draw_transparent,
+                    batch_range: 0..1,
+                    extra_index: PhaseItemExtraIndex::None,
+                    indexed: index_slab.is_some(),
+                }),
+            }
+        }
+    }
+}
+
+/// Queues the entities into the opaque or alpha mask prepass of every view with a depth, normal
+/// or motion vector prepass.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_instanced_material_prepass<M>(
+    prepass_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    alpha_mask_prepass_draw_functions: Res<DrawFunctions<AlphaMask3dPrepass>>,
+    prepass_pipeline: Res<InstancedMaterialPrepassPipeline<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedMaterialPrepassPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<RenderMesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    render_materials: Res<RenderAssets<PreparedInstancedMaterial<M>>>,
+    material_meshes: Query<(
+        Entity,
+        &MainEntity,
+        &InstancedMeshMaterial<M>,
+        &InstanceMaterialData,
+        Has<InstancePreviousData>,
+    )>,
+    mesh_allocator: Res<MeshAllocator>,
+    gpu_preprocessing_support: Res<GpuPreprocessingSupport>,
+    mut prepass_render_phases: ResMut<ViewBinnedRenderPhases<Opaque3dPrepass>>,
+    mut alpha_mask_prepass_render_phases: ResMut<ViewBinnedRenderPhases<AlphaMask3dPrepass>>,
+    ticks: SystemChangeTick,
+    arena: Option<Res<InstanceCullArena>>,
+    views: Query<(
+        &ExtractedView,
+        &Msaa,
+        Has<DepthPrepass>,
+        Has<NormalPrepass>,
+        Has<MotionVectorPrepass>,
+    )>,
+) where
+    M: InstancedMaterial,
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_prepass = prepass_draw_functions
+        .read()
+        .id::<DrawInstancedPrepass<M>>();
+    let draw_alpha_mask_prepass = alpha_mask_prepass_draw_functions
+        .read()
+        .id::<DrawInstancedPrepass<M>>();
+
+    for (view, msaa, depth_prepass, normal_prepass, motion_vector_prepass) in &views {
+        let (Some(prepass_phase), Some(alpha_mask_prepass_phase)) = (
+            prepass_render_phases.get_mut(&view.retained_view_entity),
+            alpha_mask_prepass_render_phases.get_mut(&view.retained_view_entity),
+        ) else {
+            continue;
+        };
+
+        let mut view_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+
+        if depth_prepass {
+            view_key |= MeshPipelineKey::DEPTH_PREPASS;
+        }
+        if normal_prepass {
+            view_key |= MeshPipelineKey::NORMAL_PREPASS;
+        }
+        if motion_vector_prepass {
+            view_key |= MeshPipelineKey::MOTION_VECTOR_PREPASS;
+        }
+
+        for (entity, main_entity, h_material, instance_data, previous_data) in &material_meshes {
+            let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(*main_entity)
+            else {
+                continue;
+            };
+            let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+                continue;
+            };
+            let Some(prepared_material) = render_materials.get(&h_material.0) else {
+                continue;
+            };
+
+            let alpha_mode = prepared_material.alpha_mode;
+            // Blended entities don't write depth.
+            if alpha_mode == InstancedAlphaMode::Blend {
+                continue;
+            }
+
+            let arena_chunk = arena.as_ref().and_then(|arena| arena.chunks.get(&entity));
+            if arena_chunk.is_some_and(|chunk| chunk.batch_draw_count == 0) {
+                continue;
+            }
+
+            let key = InstancedMaterialPrepassPipelineKey {
+                mesh_key: view_key
+                    | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology())
+                    | alpha_mode.pipeline_key(),
+                shadow_pass: false,
+                previous_instances: motion_vector_prepass && previous_data,
+                instance_layout: instance_data.instances.layout(),
+                batched: arena_chunk.is_some(),
+                bind_group_data: prepared_material.key.clone(),
+            };
+
+            let pipeline = pipelines
+                .specialize(&pipeline_cache, &prepass_pipeline, key, &mesh.layout)
+                .unwrap();
+
+            let (vertex_slab, index_slab) = mesh_allocator.mesh_slabs(&mesh_instance.mesh_asset_id);
+
+            let batch_set_key = OpaqueNoLightmap3dBatchSetKey {
+                pipeline,
+                draw_function: draw_prepass,
+                material_bind_group_index: None,
+                vertex_slab: vertex_slab.unwrap_or_default(),
+                index_slab,
+            };
+            let bin_key = OpaqueNoLightmap3dBinKey {
+                asset_id: mesh_instance.mesh_asset_id.into(),
+            };
+            let phase_type = BinnedRenderPhaseType::mesh(
+                mesh_instance.should_batch(),
+                &gpu_preprocessing_support,
             );
+
+            match alpha_mode {
+                InstancedAlphaMode::Opaque => prepass_phase.add(
+                    batch_set_key,
+                    bin_key,
+                    (entity, *main_entity),
+                    mesh_instance.current_uniform_index,
+                    phase_type,
+                    ticks.this_run(),
+                ),
+                InstancedAlphaMode::Mask(_) => alpha_mask_prepass_phase.add(
+                    OpaqueNoLightmap3dBatchSetKey {
+                        draw_function: draw_alpha_mask_prepass,
+                        ..batch_set_key
+                    },
+                    bin_key,
+                    (entity, *main_entity),
+                    mesh_instance.current_uniform_index,
+                    phase_type,
+                    ticks.this_run(),
+                ),
+                InstancedAlphaMode::Blend => {}
+            }
+        }
+    }
+}
+
+/// Queues every shadow casting entity into the shadow views of all lights.
+///
+/// Instances cover large areas, so the entities aren't tested against the visible entities of
+/// the lights. Entities of the [`InstanceCullArena`] aren't batched here, whether they cast
+/// shadows differs per entity.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_instanced_material_shadows<M>(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    prepass_pipeline: Res<InstancedMaterialPrepassPipeline<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedMaterialPrepassPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<RenderMesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    render_materials: Res<RenderAssets<PreparedInstancedMaterial<M>>>,
+    material_meshes: Query<(
+        Entity,
+        &MainEntity,
+        &InstancedMeshMaterial<M>,
+        &InstanceMaterialData,
+        Option<&InstanceShadowSettings>,
+        Option<&InstanceLods>,
+    )>,
+    mesh_allocator: Res<MeshAllocator>,
+    gpu_preprocessing_support: Res<GpuPreprocessingSupport>,
+    mut shadow_render_phases: ResMut<ViewBinnedRenderPhases<Shadow>>,
+    ticks: SystemChangeTick,
+    view_lights: Query<(&ViewLightEntities, Option<&RenderLayers>), With<ExtractedView>>,
+    view_light_entities: Query<(&LightEntity, &ExtractedView)>,
+    arena: Option<Res<InstanceCullArena>>,
+) where
+    M: InstancedMaterial,
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_shadow = shadow_draw_functions.read().id::<DrawInstancedShadow<M>>();
+
+    for (view_lights, camera_layers) in &view_lights {
+        let camera_layers = camera_layers.unwrap_or_default();
+
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let Ok((light_entity, extracted_view_light)) =
+                view_light_entities.get(view_light_entity)
+            else {
+                continue;
+            };
+            let Some(shadow_phase) =
+                shadow_render_phases.get_mut(&extracted_view_light.retained_view_entity)
+            else {
+                continue;
+            };
+
+            let mut view_key = MeshPipelineKey::DEPTH_PREPASS;
+            if matches!(light_entity, LightEntity::Directional { .. }) {
+                view_key |= MeshPipelineKey::UNCLIPPED_DEPTH_ORTHO;
+            }
+
+            for (entity, main_entity, h_material, instance_data, shadow_settings, lods) in
+                &material_meshes
+            {
+                let Some(mesh_instance) =
+                    render_mesh_instances.render_mesh_queue_data(*main_entity)
+                else {
+                    continue;
+                };
+                if !mesh_instance
+                    .flags
+                    .contains(RenderMeshInstanceFlags::SHADOW_CASTER)
+                {
+                    continue;
+                }
+
+                let mesh_layers = mesh_instance
+                    .shared
+                    .render_layers
+                    .as_ref()
+                    .unwrap_or_default();
+                if !camera_layers.intersects(mesh_layers) {
+                    continue;
+                }
+
+                let Some(prepared_material) = render_materials.get(&h_material.0) else {
+                    continue;
+                };
+                if !prepared_material.casts_shadows {
+                    continue;
+                }
+
+                let mesh_asset_id = shadow_settings
+                    .map_or(mesh_instance.mesh_asset_id, |settings| {
+                        settings.mesh(mesh_instance.mesh_asset_id, lods)
+                    });
+                let Some(mesh) = meshes.get(mesh_asset_id) else {
+                    continue;
+                };
+
+                // Blended entities cast opaque shadows.
+                let key = InstancedMaterialPrepassPipelineKey {
+                    mesh_key: view_key
+                        | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology())
+                        | prepared_material.alpha_mode.pipeline_key(),
+                    shadow_pass: true,
+                    previous_instances: false,
+                    instance_layout: instance_data.instances.layout(),
+                    // The source instances of the arena select their uniforms like batches.
+                    batched: arena
+                        .as_ref()
+                        .is_some_and(|arena| arena.chunks.contains_key(&entity)),
+                    bind_group_data: prepared_material.key.clone(),
+                };
+
+                let pipeline = pipelines
+                    .specialize(&pipeline_cache, &prepass_pipeline, key, &mesh.layout)
+                    .unwrap();
+
+                let (vertex_slab, index_slab) = mesh_allocator.mesh_slabs(&mesh_asset_id);
+
+                shadow_phase.add(
+                    ShadowBatchSetKey {
+                        pipeline,
+                        draw_function: draw_shadow,
+                        material_bind_group_index: None,
+                        vertex_slab: vertex_slab.unwrap_or_default(),
+                        index_slab,
+                    },
+                    ShadowBinKey {
+                        asset_id: mesh_asset_id.into(),
+                    },
+                    (entity, *main_entity),
+                    mesh_instance.current_uniform_index,
+                    BinnedRenderPhaseType::mesh(
+                        mesh_instance.should_batch(),
+                        &gpu_preprocessing_support,
+                    ),
+                    ticks.this_run(),
+                );
+            }
         }
     }
 }
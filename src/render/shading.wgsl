@@ -1,4 +1,7 @@
 #import bevy_eidolon::render::bindings::{material, instance_uniforms}
+#ifdef BATCHED_INSTANCES
+#import bevy_eidolon::render::bindings::load_instance_uniforms
+#endif
 #import bevy_eidolon::render::io_types::{VertexOutput}
 
 
@@ -7,6 +10,9 @@ fn fragment(
     in: VertexOutput,
     @builtin(front_facing) is_front: bool,
 ) -> @location(0) vec4<f32> {
+#ifdef BATCHED_INSTANCES
+    load_instance_uniforms(in.uniform_index);
+#endif
 
 #ifdef MATERIAL_DEBUG
     final_color = material.debug_color;
@@ -16,5 +22,13 @@ fn fragment(
     bevy_pbr::pbr_functions::visibility_range_dither(in.clip_position, in.visibility_range_dither);
 #endif
 
-    return instance_uniforms.color;
+    let color = instance_uniforms.color * in.color;
+
+#ifdef MAY_DISCARD
+    if (color.a < instance_uniforms.alpha_cutoff) {
+        discard;
+    }
+#endif
+
+    return color;
 }
@@ -8,5 +8,48 @@ struct InstanceUniforms {
     color: vec4<f32>,
     visibility_range: vec4<f32>,
     world_from_local: mat4x4<f32>,
+    previous_world_from_local: mat4x4<f32>,
+    shadow_density: f32,
+    alpha_cutoff: f32,
 };
 
+#ifdef BATCHED_INSTANCES
+// Element of the uniforms of batched draws, padded to the stride of the uniform buffer.
+struct BatchedInstanceUniforms {
+    uniforms: InstanceUniforms,
+#ifdef INSTANCE_UNIFORMS_PADDING
+    _padding: array<vec4<f32>, #{INSTANCE_UNIFORMS_PADDING}>,
+#endif
+};
+#endif
+
+
+// Same layout as the instance vertex buffer.
+#ifdef ORIENTED_INSTANCES
+struct PreviousInstance {
+    position: vec3<f32>,
+    index: u32,
+    rotation: vec4<f32>,
+    scale: vec3<f32>,
+    // Packed `Unorm8x4`.
+    color: u32,
+#ifdef INSTANCE_EXTRA_WORDS
+    // Fields of a custom `InstanceType`, copied as is.
+    extra: array<u32, #{INSTANCE_EXTRA_WORDS}>,
+#endif
+};
+#else
+struct PreviousInstance {
+    pos_and_scale: vec4<f32>,
+    rotation: f32,
+    index: u32,
+    // Packed `Unorm8x4`.
+    color: u32,
+    // `InstanceData::uniform_index`, see `BATCHED_INSTANCES`.
+    uniform_index: u32,
+#ifdef INSTANCE_EXTRA_WORDS
+    // Fields of a custom `InstanceType`, copied as is.
+    extra: array<u32, #{INSTANCE_EXTRA_WORDS}>,
+#endif
+};
+#endif
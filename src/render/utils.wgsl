@@ -1,5 +1,7 @@
 #define_import_path bevy_eidolon::render::utils
 
+#import bevy_pbr::utils::rand_f
+
 fn calculate_instance_world_matrix(
     i_pos_scale: vec4<f32>,
     i_rotation: f32,
@@ -21,6 +23,42 @@ fn calculate_instance_world_matrix(
     return parent_transform * instance_local;
 }
 
+fn calculate_oriented_instance_world_matrix(
+    i_position: vec3<f32>,
+    i_rotation: vec4<f32>,
+    i_scale: vec3<f32>,
+    parent_transform: mat4x4<f32>
+) -> mat4x4<f32> {
+    let q = i_rotation;
+    let x2 = q.x + q.x;
+    let y2 = q.y + q.y;
+    let z2 = q.z + q.z;
+    let xx = q.x * x2;
+    let xy = q.x * y2;
+    let xz = q.x * z2;
+    let yy = q.y * y2;
+    let yz = q.y * z2;
+    let zz = q.z * z2;
+    let wx = q.w * x2;
+    let wy = q.w * y2;
+    let wz = q.w * z2;
+
+    let instance_local = mat4x4<f32>(
+        vec4<f32>(1.0 - (yy + zz), xy + wz, xz - wy, 0.0) * i_scale.x,
+        vec4<f32>(xy - wz, 1.0 - (xx + zz), yz + wx, 0.0) * i_scale.y,
+        vec4<f32>(xz + wy, yz - wx, 1.0 - (xx + yy), 0.0) * i_scale.z,
+        vec4<f32>(i_position, 1.0)
+    );
+
+    return parent_transform * instance_local;
+}
+
+// Stable pseudo random value in [0, 1) of an instance, see `InstanceDensityFalloff`.
+fn hash_noise(index: u32) -> f32 {
+    var state = index;
+    return rand_f(&state);
+}
+
 #ifdef VISIBILITY_RANGE_DITHER
 
 #import bevy_pbr::mesh_view_bindings::view
@@ -1,13 +1,53 @@
-use bevy_ecs::resource::Resource;
+use crate::components::{
+    InstanceBoundingSphere, InstanceMaterialData, InstanceUniforms, InstancedCombinedBindGroup,
+};
+use crate::material::InstancedMaterial;
+
+use bevy_asset::{AssetEvent, AssetId, Assets};
+use bevy_camera::primitives::MeshAabb;
+use bevy_derive::{Deref, DerefMut};
+use bevy_ecs::{
+    entity::{Entity, EntityHashMap},
+    message::MessageReader,
+    resource::Resource,
+    system::{Res, ResMut},
+    world::{FromWorld, World},
+};
 use bevy_math::prelude::*;
-use bevy_render::render_resource::{BindGroup, Buffer, ShaderType};
+use bevy_mesh::Mesh;
+use bevy_render::{
+    extract_resource::ExtractResource,
+    render_resource::{BindGroup, Buffer, CachedComputePipelineId, ShaderType},
+    renderer::RenderDevice,
+    sync_world::MainEntityHashMap,
+};
+use bevy_transform::prelude::GlobalTransform;
 
+use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
 
+use std::collections::HashMap;
+
 #[derive(Clone, Copy, Pod, Zeroable, Default, ShaderType)]
 #[repr(C)]
 pub struct CameraCullData {
     pub view_pos: Vec4,
+    /// Frustum half-spaces (left, right, top, bottom, near, far) as `normal_d`,
+    /// with normals pointing towards the interior.
+    pub frustum: [Vec4; 6],
+    /// Projection matrix of the view, used for screen-size culling.
+    pub clip_from_view: Mat4,
+    /// Viewport (x, y, width, height) of the view in pixels.
+    pub viewport: Vec4,
+    /// `clip_from_world` of the frame the depth pyramid was built in.
+    pub occlusion_clip_from_world: Mat4,
+    /// Viewport (x, y, width, height) of the frame the depth pyramid was built in.
+    pub occlusion_viewport: Vec4,
+    /// Number of mips in the depth pyramid, `0` if occlusion culling is unavailable.
+    pub depth_pyramid_mip_count: u32,
+    /// See [`ViewCullBuffer::view_index`](crate::components::ViewCullBuffer::view_index).
+    pub view_index: u32,
+    pub _padding: [u32; 2],
 }
 
 #[derive(Clone, Copy, Pod, Zeroable, Default, ShaderType)]
@@ -15,10 +55,249 @@ pub struct CameraCullData {
 pub struct LodCullData {
     pub visibility_range: Vec4,
     pub world_from_local: Mat4,
+    /// Bounding sphere of a single instance in mesh space, `xyz` is the center and `w` the radius.
+    pub bounding_sphere: Vec4,
+    /// Start distances of LOD levels 1 to 4, unused levels are `f32::MAX`.
+    pub lod_distances: Vec4,
+    /// Start distance, end distance and density at the end of the density falloff.
+    pub density_falloff: Vec4,
+    /// See [`InstanceCullFlags`].
+    pub flags: u32,
+    /// Number of LOD levels including LOD 0.
+    pub lod_count: u32,
+    /// Projected diameter in pixels below which instances are culled, `0.0` to disable.
+    pub min_screen_size: f32,
+    pub _padding: u32,
 }
 
-#[derive(Resource)]
-pub struct GlobalCullBuffer {
-    pub buffer: Buffer,
+bitflags! {
+    #[repr(C)]
+    #[derive(Clone, Debug, Copy, PartialEq, Eq, Hash, Pod, Zeroable)]
+    pub struct InstanceCullFlags: u32 {
+        const OCCLUSION = 1 << 0;
+        const DENSITY_FALLOFF = 1 << 1;
+        const DENSITY_SCALE_COMPENSATION = 1 << 2;
+        /// Set for chunks of non-indexed meshes in the [`InstanceCullArena`].
+        const NON_INDEXED = 1 << 3;
+        const STABLE_COMPACTION = 1 << 4;
+        const SORT = 1 << 5;
+    }
+}
+
+/// Number of views culled by the GPU cull pass this frame.
+#[derive(Resource, Default, Clone, Copy, Deref)]
+pub struct CullViewCount(pub u32);
+
+/// Render world copy of [`GpuComputeCullPlugin::stable_compaction`](crate::cull::plugin::GpuComputeCullPlugin::stable_compaction).
+#[derive(Resource, Default, Clone, Copy, Deref)]
+pub struct GlobalStableCompaction(pub bool);
+
+/// Bounding spheres of the meshes' `Aabb`s, the default [`InstanceBoundingSphere`] of entities
+/// without one.
+#[derive(Resource, ExtractResource, Default, Clone, Deref, DerefMut)]
+pub struct MeshBoundingSpheres(pub HashMap<AssetId<Mesh>, InstanceBoundingSphere>);
+
+impl MeshBoundingSpheres {
+    /// The bounding sphere of an entity's instances, falls back to a unit sphere for meshes
+    /// without positions or whose data isn't kept in the main world.
+    pub fn resolve(
+        &self,
+        bounding_sphere: Option<&InstanceBoundingSphere>,
+        mesh: Option<AssetId<Mesh>>,
+    ) -> InstanceBoundingSphere {
+        bounding_sphere
+            .or_else(|| self.get(&mesh?))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Keeps the [`MeshBoundingSpheres`] in sync with the mesh assets, before the render world
+/// takes the data of render-only meshes.
+pub fn update_mesh_bounding_spheres(
+    mut bounding_spheres: ResMut<MeshBoundingSpheres>,
+    mut events: MessageReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+) {
+    for event in events.read() {
+        match *event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                match meshes.get(id).and_then(|mesh| mesh.compute_aabb()) {
+                    Some(aabb) => {
+                        bounding_spheres.insert(
+                            id,
+                            InstanceBoundingSphere {
+                                center: aabb.center.into(),
+                                radius: aabb.half_extents.length(),
+                            },
+                        );
+                    }
+                    None => {
+                        bounding_spheres.remove(&id);
+                    }
+                }
+            }
+            AssetEvent::Removed { id } => {
+                bounding_spheres.remove(&id);
+            }
+            AssetEvent::Unused { .. } | AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+}
+
+/// How `GpuCullCompute` instances are stored, culled and drawn.
+#[derive(Resource, ExtractResource, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuCullMode {
+    /// Every entity has its own buffers, cull dispatch and indirect draws.
+    #[default]
+    PerEntity,
+    /// All instances share one [`InstanceCullArena`] with a chunk descriptor per entity and
+    /// are culled in a single dispatch per view. Entities sharing mesh and material are batched
+    /// and draw all their LOD levels with one multi-draw-indirect call, selecting their uniforms
+    /// per instance. Without `Features::MULTI_DRAW_INDIRECT` the draws of a batch are issued
+    /// one by one.
+    ///
+    /// Requires `Features::INDIRECT_FIRST_INSTANCE` and storage buffers in vertex shaders,
+    /// otherwise falls back to [`GpuCullMode::PerEntity`]. Entities whose LOD meshes don't share
+    /// the mesh buffers of LOD 0 also use the per-entity path.
+    Arena,
+}
+
+/// Descriptor of a single entity in the [`InstanceCullArena`].
+#[derive(Clone, Copy, Pod, Zeroable, Default, ShaderType)]
+#[repr(C)]
+pub struct ArenaChunkData {
+    pub lod: LodCullData,
+    /// Index of the first instance in the arena source buffer.
+    pub first_instance: u32,
+    pub instance_count: u32,
+    /// Index of the first draw within a view's range of the indexed or non-indexed arena
+    /// indirect buffer.
+    pub first_draw: u32,
+    /// Index of the first instance within a view's range of the arena output buffer.
+    pub first_output: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable, Default, ShaderType)]
+#[repr(C)]
+pub struct ArenaCullData {
+    /// Number of draws per view in the indexed arena indirect buffer.
+    pub indexed_view_draw_stride: u32,
+    /// Number of draws per view in the non-indexed arena indirect buffer.
+    pub non_indexed_view_draw_stride: u32,
+    /// Number of instances per view in the arena output buffer.
+    pub view_output_stride: u32,
+    pub chunk_count: u32,
+}
+
+/// Shared instance arena of [`GpuCullMode::Arena`], rebuilt every frame.
+#[derive(Resource, Default)]
+pub struct InstanceCullArena {
+    /// Render entities culled in the arena.
+    pub chunks: EntityHashMap<InstanceArenaChunk>,
+    pub buffers: Option<InstanceArenaBuffers>,
+    /// Whether the draws of a batch are issued with a single multi-draw-indirect call.
+    pub multi_draw_indirect: bool,
+}
+
+/// Draws of a single entity in the [`InstanceCullArena`], one per LOD level.
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceArenaChunk {
+    /// See [`ArenaChunkData::first_draw`].
+    pub first_draw: u32,
+    pub draw_count: u32,
+    pub indexed: bool,
+    /// Range of the entity's instances in [`InstanceArenaBuffers::source`].
+    pub first_instance: u32,
+    pub instance_count: u32,
+    /// Number of draws of the batch this entity leads, starting at `first_draw`. `0` if the
+    /// entity is drawn by the batch of a previous entity with the same mesh and material.
+    pub batch_draw_count: u32,
+}
+
+pub struct InstanceArenaBuffers {
+    pub source: Buffer,
+    pub chunks: Buffer,
+    pub arena_data: Buffer,
+    pub output: Buffer,
+    pub indexed_indirect: Buffer,
+    pub indirect: Buffer,
+    /// Only allocated if a chunk uses stable compaction.
+    pub visibility: Option<Buffer>,
     pub bind_group: BindGroup,
+    pub instance_count: u32,
+    /// Entity, instance count and uniform index of the chunks packed into `source`, in order.
+    /// The instances are packed again whenever this changes.
+    pub packing: Vec<(Entity, u32, u32)>,
+    pub chunk_count: u32,
+    pub view_count: u32,
+    /// See [`ArenaCullData::indexed_view_draw_stride`].
+    pub indexed_view_draw_stride: u32,
+    /// See [`ArenaCullData::non_indexed_view_draw_stride`].
+    pub non_indexed_view_draw_stride: u32,
+}
+
+/// The last extracted [`InstanceMaterialData`] and `GlobalTransform` of each main entity, with
+/// all instances marked as changed.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ExtractedInstanceMaterialData(
+    pub MainEntityHashMap<(InstanceMaterialData, GlobalTransform)>,
+);
+
+/// Cull pipelines of the entities whose material has a custom [`InstancedMaterial::cull_shader`]
+/// or whose instances have a non-default [`InstanceLayout`](crate::components::InstanceLayout),
+/// rebuilt every frame.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct InstancedCullPipelines(pub EntityHashMap<InstancedMaterialCullPipelines>);
+
+pub struct InstancedMaterialCullPipelines {
+    pub pipeline_id: CachedComputePipelineId,
+    pub non_indexed_pipeline_id: CachedComputePipelineId,
+    pub compact_pipeline_id: CachedComputePipelineId,
+    pub non_indexed_compact_pipeline_id: CachedComputePipelineId,
+    /// Bindings of the material, bound at group 2 by custom cull shaders.
+    pub material_bind_group: Option<BindGroup>,
+}
+
+/// The [`InstanceUniforms`] of all entities with material `M`, bound at
+/// [`INSTANCE_BINDING_INDEX`](crate::render::prepare::INSTANCE_BINDING_INDEX) with a dynamic
+/// offset per entity, see
+/// [`PreparedInstanceUniforms::offset`](crate::components::PreparedInstanceUniforms::offset).
+#[derive(Resource)]
+pub struct InstanceUniformsBuffer<M: InstancedMaterial> {
+    pub buffer: Option<Buffer>,
+    /// Bytes between the uniforms of two entities, aligned to the device's uniform offset
+    /// alignment.
+    pub stride: u64,
+    /// The uploaded uniforms, only the uniforms of changed entities are uploaded again.
+    pub contents: Vec<u8>,
+    /// The entities in the order of their uniforms, a changed packing uploads all uniforms again.
+    pub packing: Vec<Entity>,
+    /// Bind groups of the material bindings and the buffer, shared by all entities of a
+    /// material.
+    pub bind_groups: HashMap<AssetId<M>, InstancedCombinedBindGroup>,
+}
+
+impl<M: InstancedMaterial> FromWorld for InstanceUniformsBuffer<M> {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            buffer: None,
+            stride: instance_uniforms_stride(world.resource::<RenderDevice>()),
+            contents: Vec::new(),
+            packing: Vec::new(),
+            bind_groups: HashMap::new(),
+        }
+    }
+}
+
+/// See [`InstanceUniformsBuffer::stride`].
+pub fn instance_uniforms_stride(render_device: &RenderDevice) -> u64 {
+    let alignment = render_device.limits().min_uniform_buffer_offset_alignment as u64;
+    (size_of::<InstanceUniforms>() as u64).next_multiple_of(alignment)
+}
+
+/// Batched draws of the [`GpuCullMode::Arena`] read the [`InstanceUniformsBuffer`] as a storage
+/// buffer in the vertex and fragment shaders, which WebGL2 doesn't support.
+pub fn batched_instances_supported(render_device: &RenderDevice) -> bool {
+    render_device.limits().max_storage_buffers_per_shader_stage > 0
 }